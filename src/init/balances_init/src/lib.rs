@@ -0,0 +1,86 @@
+extern crate wee_alloc;
+
+use many_error::ManyError;
+use many_identity::Address;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use storage_ledger::{parse_amount, LedgerAccount};
+use storage_tokens::Tokens;
+
+// Use `wee_alloc` as the global allocator.
+#[global_allocator]
+static ALLOC: wee_alloc::WeeAlloc = wee_alloc::WeeAlloc::INIT;
+
+#[derive(Deserialize)]
+struct LedgerConfig {
+    /// Symbol address to ticker, for symbols not registered by `tokens_init`.
+    #[serde(default)]
+    symbols: BTreeMap<String, String>,
+
+    /// Account address to ticker to amount.
+    #[serde(default)]
+    balances: BTreeMap<String, BTreeMap<String, String>>,
+}
+
+fn init_(arg: Vec<u8>) -> Result<(), ManyError> {
+    let config: LedgerConfig = serde_json::from_slice(&arg).map_err(ManyError::unknown)?;
+
+    let mut symbols = storage_ledger::symbols()?;
+    for (symbol, ticker) in config.symbols {
+        let address = Address::from_str(&symbol)
+            .map_err(|e| ManyError::unknown(format!("Invalid symbol {symbol}: {e}")))?;
+        match symbols.get(&address) {
+            None => {
+                symbols.insert(address, ticker);
+            }
+            Some(existing) if *existing == ticker => {}
+            Some(existing) => {
+                return Err(ManyError::unknown(format!(
+                    "Symbol {symbol} is already registered as {existing}."
+                )));
+            }
+        }
+    }
+    storage_ledger::set_symbols(&symbols)?;
+
+    let tickers: BTreeMap<&str, Address> = symbols
+        .iter()
+        .map(|(address, ticker)| (ticker.as_str(), *address))
+        .collect();
+
+    let tokens = Tokens::new();
+    for (account, balances) in config.balances {
+        let address = Address::from_str(&account)
+            .map_err(|e| ManyError::unknown(format!("Invalid account {account}: {e}")))?;
+        let ledger_account: LedgerAccount = address.into();
+
+        for (ticker, amount) in balances {
+            let symbol = tickers.get(ticker.as_str()).ok_or_else(|| {
+                ManyError::unknown(format!("Unknown symbol {ticker} for account {account}."))
+            })?;
+            let amount = parse_amount(&amount).map_err(|e| {
+                ManyError::unknown(format!(
+                    "Invalid amount {amount:?} for account {account}, symbol {ticker}: {e}."
+                ))
+            })?;
+
+            // Keep the supply of tokens in sync with their balances.
+            if let Some(mut info) = tokens.get(*symbol)? {
+                info.supply -= ledger_account.balance(*symbol);
+                info.supply += amount.clone();
+                tokens.set(*symbol, &info)?;
+            }
+            ledger_account.set(*symbol, amount);
+        }
+    }
+
+    Ok(())
+}
+
+#[export_name = "init"]
+pub fn init() {
+    if let Err(e) = many_wasm::many::init_arg().and_then(init_) {
+        panic!("Could not initialize balances: {e}");
+    }
+}
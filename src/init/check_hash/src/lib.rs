@@ -0,0 +1,59 @@
+extern crate wee_alloc;
+
+use many_error::ManyError;
+use many_wasm::store::Storage;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+
+// Use `wee_alloc` as the global allocator.
+#[global_allocator]
+static ALLOC: wee_alloc::WeeAlloc = wee_alloc::WeeAlloc::INIT;
+
+fn default_storage() -> String {
+    "ledger".to_string()
+}
+
+#[derive(Deserialize)]
+struct CheckHashConfig {
+    /// Expected hash (in hex) of `storage`.
+    hash: Option<String>,
+
+    /// The storage checked against `hash`. Defaults to `ledger`.
+    #[serde(default = "default_storage")]
+    storage: String,
+
+    /// Expected hashes (in hex) of other storages, by name.
+    #[serde(default)]
+    storages: BTreeMap<String, String>,
+}
+
+fn check(name: &str, expected: &str) -> Result<(), ManyError> {
+    let actual = hex::encode(Storage::by_name(name).hash());
+    if actual.eq_ignore_ascii_case(expected) {
+        Ok(())
+    } else {
+        Err(ManyError::unknown(format!(
+            "Invalid hash for storage {name}. Expected {expected}, was {actual}."
+        )))
+    }
+}
+
+fn init_(arg: Vec<u8>) -> Result<(), ManyError> {
+    let config: CheckHashConfig = serde_json::from_slice(&arg).map_err(ManyError::unknown)?;
+
+    if let Some(hash) = &config.hash {
+        check(&config.storage, hash)?;
+    }
+    for (name, hash) in &config.storages {
+        check(name, hash)?;
+    }
+
+    Ok(())
+}
+
+#[export_name = "init"]
+pub fn init() {
+    if let Err(e) = many_wasm::many::init_arg().and_then(init_) {
+        panic!("Genesis hash check failed: {e}");
+    }
+}
@@ -0,0 +1,82 @@
+extern crate wee_alloc;
+
+use many_error::ManyError;
+use many_identity::Address;
+use many_types::ledger::TokenAmount;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use storage_ledger::parse_amount;
+use storage_tokens::{TokenInfo, Tokens};
+
+// Use `wee_alloc` as the global allocator.
+#[global_allocator]
+static ALLOC: wee_alloc::WeeAlloc = wee_alloc::WeeAlloc::INIT;
+
+/// A token created at genesis.
+#[derive(Deserialize)]
+struct TokenConfig {
+    name: String,
+    symbol: String,
+    precision: u64,
+    owner: Option<String>,
+
+    /// Account address to amount, minted at genesis.
+    #[serde(default)]
+    distribution: BTreeMap<String, String>,
+}
+
+/// Map of symbol address to token.
+type TokensConfig = BTreeMap<String, TokenConfig>;
+
+fn init_(arg: Vec<u8>) -> Result<(), ManyError> {
+    let config: TokensConfig = serde_json::from_slice(&arg).map_err(ManyError::unknown)?;
+    let tokens = Tokens::new();
+
+    for (symbol, token) in config {
+        let address = Address::from_str(&symbol)
+            .map_err(|e| ManyError::unknown(format!("Invalid symbol {symbol}: {e}")))?;
+        let owner = token
+            .owner
+            .map(|o| Address::from_str(&o))
+            .transpose()
+            .map_err(|e| ManyError::unknown(format!("Invalid owner for {symbol}: {e}")))?;
+
+        let distribution = token
+            .distribution
+            .iter()
+            .map(|(account, amount)| {
+                let address = Address::from_str(account).map_err(|e| {
+                    ManyError::unknown(format!("Invalid account {account} for {symbol}: {e}"))
+                })?;
+                let amount = parse_amount(amount).map_err(|e| {
+                    ManyError::unknown(format!(
+                        "Invalid amount {amount:?} for account {account}, symbol {symbol}: {e}."
+                    ))
+                })?;
+                Ok((address, amount))
+            })
+            .collect::<Result<BTreeMap<_, _>, ManyError>>()?;
+
+        tokens.set(
+            address,
+            &TokenInfo {
+                name: token.name,
+                symbol: token.symbol,
+                precision: token.precision,
+                supply: TokenAmount::from(0u64),
+                owner,
+            },
+        )?;
+        tokens.mint(address, &distribution)?;
+    }
+
+    Ok(())
+}
+
+#[export_name = "init"]
+pub fn init() {
+    if let Err(e) = many_wasm::many::init_arg().and_then(init_) {
+        panic!("Could not create tokens: {e}");
+    }
+}
@@ -22,11 +22,15 @@ pub fn link(linker: &mut Linker<WasmContext>) -> Result<(), Error> {
         payload_copy,
         sender_size,
         sender_copy,
+        identity_copy,
         error_create,
         error_message,
         error_argument,
         return_error,
         return_data,
+        call,
+        call_result_size,
+        call_result_copy,
     });
 
     decl_many_imports!((linker, store) => {
@@ -34,6 +38,9 @@ pub fn link(linker: &mut Linker<WasmContext>) -> Result<(), Error> {
         get,
         set,
         size,
+        hash,
+        del,
+        next_key,
     });
 
     wasi_snapshot_preview1::register_wasi(linker)?;
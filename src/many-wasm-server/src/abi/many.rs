@@ -1,5 +1,7 @@
 use crate::abi::{_read, _read_str, _store};
+use crate::wasm_engine::run_endpoint;
 use crate::wasm_engine::state::WasmContext;
+use many_protocol::RequestMessage;
 use std::cmp::min;
 use wasi_common::Error;
 use wasmtime::Caller;
@@ -32,6 +34,15 @@ pub fn sender_copy(mut caller: Caller<'_, WasmContext>, ptr: u32) -> Result<u32,
     })
 }
 
+pub fn identity_copy(mut caller: Caller<'_, WasmContext>, ptr: u32) -> Result<u32, Error> {
+    let bytes = caller.data().identity().to_vec();
+
+    _store(&mut caller, ptr, bytes.len() as u32, |data| {
+        data.copy_from_slice(&bytes);
+        Ok(bytes.len() as u32)
+    })
+}
+
 pub fn error_create(mut caller: Caller<'_, WasmContext>, code: i32) -> Result<u32, Error> {
     Ok(caller.data_mut().create_error(code).into())
 }
@@ -72,3 +83,74 @@ pub fn return_data(mut caller: Caller<'_, WasmContext>, ptr: u32, len: u32) -> R
     caller.data_mut().set_return_value(Ok(data))?;
     Ok(())
 }
+
+/// Call another endpoint, in the same transaction, with the calling module as
+/// the sender. If the call fails, all its writes are discarded. Returns a
+/// handle to the result, which can be read with `call_result_size` and
+/// `call_result_copy`.
+pub fn call(
+    mut caller: Caller<'_, WasmContext>,
+    endpoint_ptr: u32,
+    endpoint_len: u32,
+    payload_ptr: u32,
+    payload_len: u32,
+) -> Result<u32, Error> {
+    let endpoint = _read_str(&mut caller, endpoint_ptr, endpoint_len, |s| {
+        Ok(s.to_string())
+    })?;
+    let payload = _read(&mut caller, payload_ptr, payload_len, |data| {
+        Ok(Vec::from(data))
+    })?;
+
+    let request = {
+        let ctx = caller.data();
+        let parent = ctx.request()?.clone();
+        let module = ctx
+            .current_module()
+            .ok_or_else(|| Error::msg("Invalid context"))?;
+        ctx.module_address(module).map(|from| RequestMessage {
+            from: Some(from),
+            method: endpoint,
+            data: payload,
+            ..parent
+        })
+    };
+
+    let result = request.and_then(|request| run_endpoint(&mut caller, request));
+    Ok(caller.data_mut().create_call_result(result).into())
+}
+
+/// Size of the result of a call. For errors, this is the size of the CBOR
+/// encoded `ManyError`.
+pub fn call_result_size(caller: Caller<'_, WasmContext>, handle: u32) -> Result<u32, Error> {
+    Ok(match caller.data().get_call_result(handle.into())? {
+        Ok(data) => data.len() as u32,
+        Err(err) => minicbor::to_vec(err)
+            .map_err(|e| Error::msg(e.to_string()))?
+            .len() as u32,
+    })
+}
+
+/// Copy the result of a call and free it, so the handle is invalid afterward.
+/// Returns 0 if the call succeeded, 1 if the buffer contains a CBOR encoded
+/// `ManyError`.
+pub fn call_result_copy(
+    mut caller: Caller<'_, WasmContext>,
+    handle: u32,
+    ptr: u32,
+    len: u32,
+) -> Result<u32, Error> {
+    let (bytes, is_error) = match caller.data_mut().take_call_result(handle.into())? {
+        Ok(data) => (data, 0),
+        Err(err) => (
+            minicbor::to_vec(&err).map_err(|e| Error::msg(e.to_string()))?,
+            1,
+        ),
+    };
+
+    _store(&mut caller, ptr, len, |data| {
+        let len = min(data.len(), bytes.len());
+        data[..len].copy_from_slice(&bytes[..len]);
+        Ok(is_error)
+    })
+}
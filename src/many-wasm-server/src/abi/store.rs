@@ -68,3 +68,60 @@ pub fn set(
     let storage_ref = caller.data_mut().get_storage_mut(handle.into())?;
     storage_ref.set(key, value).map_err(|e| anyhow!("{e}"))
 }
+
+pub fn hash(
+    mut caller: Caller<'_, WasmContext>,
+    handle: u32,
+    buffer_ptr: u32,
+    buffer_len: u32,
+) -> Result<u32, Error> {
+    let storage_ref = caller.data().get_storage(handle.into())?;
+    let hash = storage_ref.hash().map_err(|e| anyhow!("{e}"))?;
+    let len = hash.len().min(buffer_len as usize);
+
+    _store(&mut caller, buffer_ptr, len as u32, |buffer| {
+        buffer.copy_from_slice(&hash[..len]);
+        Ok(())
+    })?;
+
+    Ok(hash.len() as u32)
+}
+
+pub fn del(
+    mut caller: Caller<'_, WasmContext>,
+    handle: u32,
+    key_ptr: u32,
+    key_len: u32,
+) -> Result<(), Error> {
+    let key = _read(&mut caller, key_ptr, key_len, |key| Ok(key.to_owned()))?;
+
+    let storage_ref = caller.data_mut().get_storage_mut(handle.into())?;
+    storage_ref.del(&key).map_err(|e| anyhow!("{e}"))
+}
+
+/// Copy the first key strictly after the given one into the buffer, returning
+/// its length, or `u32::MAX` if there is no such key. If the buffer is too small,
+/// nothing is copied and the caller should retry with a bigger buffer.
+pub fn next_key(
+    mut caller: Caller<'_, WasmContext>,
+    handle: u32,
+    key_ptr: u32,
+    key_len: u32,
+    buffer_ptr: u32,
+    buffer_len: u32,
+) -> Result<u32, Error> {
+    let key = _read(&mut caller, key_ptr, key_len, |key| Ok(key.to_owned()))?;
+
+    let storage_ref = caller.data().get_storage(handle.into())?;
+    match storage_ref.next_key(&key).map_err(|e| anyhow!("{e}"))? {
+        None => Ok(u32::MAX),
+        Some(next) if next.len() > buffer_len as usize => Ok(next.len() as u32),
+        Some(next) => {
+            _store(&mut caller, buffer_ptr, next.len() as u32, |buffer| {
+                buffer.copy_from_slice(&next);
+                Ok(())
+            })?;
+            Ok(next.len() as u32)
+        }
+    }
+}
@@ -0,0 +1,279 @@
+//! Conversions between JSON and CBOR, for passing payloads on the command line.
+//!
+//! Object keys that are integers are encoded as CBOR integers, since most MANY
+//! payloads are maps with integer keys. Byte strings are written as
+//! `{"$bytes": "<hex>"}` and tagged items as `{"$tag": <n>, "value": ...}`, both
+//! ways.
+use anyhow::{anyhow, bail};
+use minicbor::data::{Tag, Type};
+use minicbor::{Decoder, Encoder};
+use serde_json::{Map, Number, Value};
+
+const BYTES_KEY: &str = "$bytes";
+const TAG_KEY: &str = "$tag";
+const TAG_VALUE_KEY: &str = "value";
+
+/// Encode a JSON value as CBOR.
+pub fn from_json(value: &Value) -> Result<Vec<u8>, anyhow::Error> {
+    let mut e = Encoder::new(Vec::new());
+    encode(&mut e, value)?;
+    Ok(e.into_writer())
+}
+
+fn encode(e: &mut Encoder<Vec<u8>>, value: &Value) -> Result<(), anyhow::Error> {
+    let err = |e: minicbor::encode::Error<std::convert::Infallible>| anyhow!("{e}");
+    match value {
+        Value::Null => {
+            e.null().map_err(err)?;
+        }
+        Value::Bool(b) => {
+            e.bool(*b).map_err(err)?;
+        }
+        Value::Number(n) => {
+            if let Some(n) = n.as_u64() {
+                e.u64(n).map_err(err)?;
+            } else if let Some(n) = n.as_i64() {
+                e.i64(n).map_err(err)?;
+            } else if let Some(n) = n.as_f64() {
+                e.f64(n).map_err(err)?;
+            }
+        }
+        Value::String(s) => {
+            e.str(s).map_err(err)?;
+        }
+        Value::Array(values) => {
+            e.array(values.len() as u64).map_err(err)?;
+            for v in values {
+                encode(e, v)?;
+            }
+        }
+        Value::Object(map) => match (map.get(BYTES_KEY), map.get(TAG_KEY), map.len()) {
+            (Some(Value::String(bytes)), _, 1) => {
+                let bytes = hex::decode(bytes).map_err(|e| anyhow!("Invalid {BYTES_KEY}: {e}"))?;
+                e.bytes(&bytes).map_err(err)?;
+            }
+            (Some(_), _, _) => bail!("{BYTES_KEY} must be the only key, with a hex string."),
+            (None, Some(tag), 2) if map.contains_key(TAG_VALUE_KEY) => {
+                let tag = tag
+                    .as_u64()
+                    .ok_or_else(|| anyhow!("{TAG_KEY} must be an unsigned integer."))?;
+                e.tag(Tag::Unassigned(tag)).map_err(err)?;
+                encode(e, &map[TAG_VALUE_KEY])?;
+            }
+            (None, Some(_), _) => bail!("{TAG_KEY} must only be used with a {TAG_VALUE_KEY} key."),
+            (None, None, _) => {
+                e.map(map.len() as u64).map_err(err)?;
+                for (k, v) in map {
+                    if let Ok(k) = k.parse::<u64>() {
+                        e.u64(k).map_err(err)?;
+                    } else if let Ok(k) = k.parse::<i64>() {
+                        e.i64(k).map_err(err)?;
+                    } else {
+                        e.str(k).map_err(err)?;
+                    }
+                    encode(e, v)?;
+                }
+            }
+        },
+    }
+    Ok(())
+}
+
+/// Decode CBOR into a JSON value.
+pub fn to_json(bytes: &[u8]) -> Result<Value, anyhow::Error> {
+    let mut d = Decoder::new(bytes);
+    let value = decode(&mut d)?;
+    if d.position() != bytes.len() {
+        bail!("Trailing bytes after CBOR value.");
+    }
+    Ok(value)
+}
+
+/// Whether the next item of an indefinite length array or map is the break
+/// marker, in which case it is consumed.
+fn is_break(d: &mut Decoder) -> Result<bool, anyhow::Error> {
+    if d.datatype().map_err(|e| anyhow!("{e}"))? == Type::Break {
+        d.set_position(d.position() + 1);
+        Ok(true)
+    } else {
+        Ok(false)
+    }
+}
+
+fn decode(d: &mut Decoder) -> Result<Value, anyhow::Error> {
+    let err = |e: minicbor::decode::Error| anyhow!("{e}");
+    Ok(match d.datatype().map_err(err)? {
+        Type::Null | Type::Undefined => {
+            d.skip().map_err(err)?;
+            Value::Null
+        }
+        Type::Bool => Value::Bool(d.bool().map_err(err)?),
+        Type::U8 | Type::U16 | Type::U32 | Type::U64 => Value::from(d.u64().map_err(err)?),
+        Type::I8 | Type::I16 | Type::I32 | Type::I64 => Value::from(d.i64().map_err(err)?),
+        Type::F32 => {
+            Number::from_f64(d.f32().map_err(err)?.into()).map_or(Value::Null, Value::Number)
+        }
+        Type::F64 => Number::from_f64(d.f64().map_err(err)?).map_or(Value::Null, Value::Number),
+        Type::Bytes => bytes_to_json(d.bytes().map_err(err)?),
+        Type::BytesIndef => {
+            let mut bytes = Vec::new();
+            for chunk in d.bytes_iter().map_err(err)? {
+                bytes.extend_from_slice(chunk.map_err(err)?);
+            }
+            bytes_to_json(&bytes)
+        }
+        Type::String => Value::String(d.str().map_err(err)?.to_string()),
+        Type::StringIndef => {
+            let mut s = String::new();
+            for chunk in d.str_iter().map_err(err)? {
+                s.push_str(chunk.map_err(err)?);
+            }
+            Value::String(s)
+        }
+        Type::Array | Type::ArrayIndef => {
+            let mut values = Vec::new();
+            match d.array().map_err(err)? {
+                Some(len) => {
+                    for _ in 0..len {
+                        values.push(decode(d)?);
+                    }
+                }
+                None => {
+                    while !is_break(d)? {
+                        values.push(decode(d)?);
+                    }
+                }
+            }
+            Value::Array(values)
+        }
+        Type::Map | Type::MapIndef => {
+            let mut map = Map::new();
+            match d.map().map_err(err)? {
+                Some(len) => {
+                    for _ in 0..len {
+                        let (k, v) = decode_entry(d)?;
+                        map.insert(k, v);
+                    }
+                }
+                None => {
+                    while !is_break(d)? {
+                        let (k, v) = decode_entry(d)?;
+                        map.insert(k, v);
+                    }
+                }
+            }
+            Value::Object(map)
+        }
+        Type::Tag => {
+            let tag = tag_number(d)?;
+            let mut map = Map::new();
+            map.insert(TAG_KEY.to_string(), Value::from(tag));
+            map.insert(TAG_VALUE_KEY.to_string(), decode(d)?);
+            Value::Object(map)
+        }
+        t => bail!("Unsupported CBOR type {t:?}."),
+    })
+}
+
+fn bytes_to_json(bytes: &[u8]) -> Value {
+    let mut map = Map::new();
+    map.insert(BYTES_KEY.to_string(), Value::String(hex::encode(bytes)));
+    Value::Object(map)
+}
+
+/// Read a tag and return its number, which `Tag` does not expose for all tags.
+fn tag_number(d: &mut Decoder) -> Result<u64, anyhow::Error> {
+    let start = d.position();
+    d.tag().map_err(|e| anyhow!("{e}"))?;
+    let header = &d.input()[start..d.position()];
+    Ok(match header {
+        [initial] => u64::from(initial & 0x1f),
+        [_, rest @ ..] => rest.iter().fold(0, |n, b| n << 8 | u64::from(*b)),
+        [] => bail!("Empty tag."),
+    })
+}
+
+fn decode_entry(d: &mut Decoder) -> Result<(String, Value), anyhow::Error> {
+    let key = match decode(d)? {
+        Value::String(s) => s,
+        k => k.to_string(),
+    };
+    Ok((key, decode(d)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn roundtrip(value: Value) {
+        let bytes = from_json(&value).unwrap();
+        assert_eq!(to_json(&bytes).unwrap(), value);
+    }
+
+    #[test]
+    fn values() {
+        roundtrip(json!(null));
+        roundtrip(json!(true));
+        roundtrip(json!(12));
+        roundtrip(json!(-12));
+        roundtrip(json!(u64::MAX));
+        roundtrip(json!(1.5));
+        roundtrip(json!("12"));
+        roundtrip(json!([1, "a", [], {}]));
+    }
+
+    #[test]
+    fn integer_keys() {
+        let value = json!({ "0": "a", "1": { "-1": [2] }, "key": 3 });
+        let bytes = from_json(&value).unwrap();
+
+        let mut d = Decoder::new(&bytes);
+        assert_eq!(d.map().unwrap(), Some(3));
+        assert_eq!(d.u8().unwrap(), 0);
+        assert_eq!(d.str().unwrap(), "a");
+        assert_eq!(d.u8().unwrap(), 1);
+        assert_eq!(d.map().unwrap(), Some(1));
+        assert_eq!(d.i8().unwrap(), -1);
+
+        assert_eq!(to_json(&bytes).unwrap(), value);
+    }
+
+    #[test]
+    fn bytes_and_tags() {
+        let value = json!({ "0": { "$bytes": "00ff" }, "1": { "$tag": 10000, "value": "a" } });
+        let bytes = from_json(&value).unwrap();
+        assert_eq!(
+            bytes,
+            [0xa2, 0x00, 0x42, 0x00, 0xff, 0x01, 0xd9, 0x27, 0x10, 0x61, b'a']
+        );
+        assert_eq!(to_json(&bytes).unwrap(), value);
+
+        roundtrip(json!({ "$bytes": "" }));
+        roundtrip(json!({ "$tag": 0, "value": { "$tag": 24, "value": { "$bytes": "01" } } }));
+        roundtrip(json!({ "$tag": u64::MAX, "value": null }));
+
+        assert!(from_json(&json!({ "$bytes": "zz" })).is_err());
+        assert!(from_json(&json!({ "$bytes": "00", "other": 1 })).is_err());
+        assert!(from_json(&json!({ "$tag": -1, "value": 1 })).is_err());
+        assert!(from_json(&json!({ "$tag": 1 })).is_err());
+    }
+
+    #[test]
+    fn indefinite_length() {
+        // [_ 1, {_ 0: (_ h'01', h'02'), 1: (_ "a", "b")}]
+        let bytes = [
+            0x9f, 0x01, 0xbf, 0x00, 0x5f, 0x41, 0x01, 0x41, 0x02, 0xff, 0x01, 0x7f, 0x61, b'a',
+            0x61, b'b', 0xff, 0xff, 0xff,
+        ];
+        let value = to_json(&bytes).unwrap();
+        assert_eq!(value, json!([1, { "0": { "$bytes": "0102" }, "1": "ab" }]));
+        roundtrip(value);
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(to_json(&[0x01, 0x02]).is_err());
+        assert!(to_json(&[0x9f, 0x01]).is_err());
+    }
+}
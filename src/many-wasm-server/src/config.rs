@@ -1,18 +1,25 @@
-use crate::storage::{memory, merk, NullKvStore, StorageLibrary, StorageRef};
+use crate::storage::{
+    memory, merk, overlay, readonly, rocksdb, sqlite, versioned, NullKvStore, StorageLibrary,
+    StorageRef,
+};
 use anyhow::anyhow;
 use either::Either;
 use many_error::ManyError;
+use many_identity::Address;
+use minicbor::{Decode, Encode};
+use schemars::JsonSchema;
 use serde::Deserializer;
 use serde_derive::{Deserialize, Serialize};
 use serde_json::Value;
 use std::borrow::Cow;
 use std::cell::RefCell;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use tracing::debug;
 
-#[derive(Serialize, Deserialize)]
-#[serde(tag = "type")]
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "type", deny_unknown_fields)]
 pub enum SingleStorageConfig {
     Memory {
         name: String,
@@ -22,17 +29,107 @@ pub enum SingleStorageConfig {
         name: String,
         path: PathBuf,
     },
+    RocksDb {
+        name: String,
+        path: PathBuf,
+        #[serde(default)]
+        hash: rocksdb::HashStrategy,
+    },
+    Sqlite {
+        name: String,
+        path: PathBuf,
+    },
     Prefixed {
         name: String,
         prefix: String,
         backend: String,
     },
+    /// Its values and history are kept in the backend under the
+    /// `\0versioned/<name>/` prefix.
+    Versioned {
+        name: String,
+        backend: String,
+        #[serde(default)]
+        pruning: versioned::PruningPolicy,
+    },
+    ReadOnly {
+        name: String,
+        backend: String,
+    },
+    /// Its hash is the one of the backend until something is committed, and
+    /// is then computed over all entries, like a memory storage.
+    Overlay {
+        name: String,
+        backend: String,
+    },
     Null {
         name: String,
     },
 }
 
+/// Decode the hex encoded keys and values of a memory storage.
+fn decode_values(values: &BTreeMap<String, String>) -> Result<Vec<(Vec<u8>, Vec<u8>)>, ManyError> {
+    values
+        .iter()
+        .map(|(key, value)| {
+            let k = hex::decode(key)
+                .map_err(|e| ManyError::unknown(format!("Key '{key}' is not valid hex: {e}.")))?;
+            let v = hex::decode(value).map_err(|e| {
+                ManyError::unknown(format!("Value of key '{key}' is not valid hex: {e}."))
+            })?;
+            Ok((k, v))
+        })
+        .collect()
+}
+
 impl SingleStorageConfig {
+    pub fn name(&self) -> &str {
+        match self {
+            SingleStorageConfig::Memory { name, .. }
+            | SingleStorageConfig::Merk { name, .. }
+            | SingleStorageConfig::RocksDb { name, .. }
+            | SingleStorageConfig::Sqlite { name, .. }
+            | SingleStorageConfig::Prefixed { name, .. }
+            | SingleStorageConfig::Versioned { name, .. }
+            | SingleStorageConfig::ReadOnly { name, .. }
+            | SingleStorageConfig::Overlay { name, .. }
+            | SingleStorageConfig::Null { name } => name,
+        }
+    }
+
+    /// The storage this one is built on, which must be created first.
+    pub fn backend(&self) -> Option<&str> {
+        match self {
+            SingleStorageConfig::Prefixed { backend, .. }
+            | SingleStorageConfig::Versioned { backend, .. }
+            | SingleStorageConfig::ReadOnly { backend, .. }
+            | SingleStorageConfig::Overlay { backend, .. } => Some(backend),
+            _ => None,
+        }
+    }
+
+    /// The database this storage is kept in, relative to the configuration.
+    pub fn path(&self) -> Option<&Path> {
+        match self {
+            SingleStorageConfig::Merk { path, .. }
+            | SingleStorageConfig::RocksDb { path, .. }
+            | SingleStorageConfig::Sqlite { path, .. } => Some(path),
+            _ => None,
+        }
+    }
+
+    /// Validate the configuration without creating the storage.
+    pub fn check(&self) -> Result<(), ManyError> {
+        if let SingleStorageConfig::Memory {
+            values: Some(values),
+            ..
+        } = self
+        {
+            decode_values(values)?;
+        }
+        Ok(())
+    }
+
     pub fn create_ref(
         self,
         storage: &mut StorageLibrary,
@@ -44,9 +141,7 @@ impl SingleStorageConfig {
                 debug!(r#type = "memory", name);
                 let mut memory = memory::MemoryStorage::default();
                 if let Some(values) = values {
-                    for (key, value) in values {
-                        let key = hex::decode(key).map_err(ManyError::unknown)?;
-                        let value = hex::decode(value).map_err(ManyError::unknown)?;
+                    for (key, value) in decode_values(&values)? {
                         memory.insert(key, value);
                     }
                 }
@@ -67,26 +162,175 @@ impl SingleStorageConfig {
                 };
                 Ok((name, StorageRef::new(merk)))
             }
+            SingleStorageConfig::RocksDb { name, path, hash } => {
+                let path = root.as_ref().join(path);
+                debug!(
+                    r#type = "rocksdb",
+                    name,
+                    path = path.to_string_lossy().as_ref(),
+                    ?hash,
+                    create_if_missing
+                );
+                let db = if create_if_missing {
+                    rocksdb::RocksDbStorage::new(&path, true, hash)?
+                } else {
+                    rocksdb::RocksDbStorage::load(&path, hash)?
+                };
+                Ok((name, StorageRef::new(db)))
+            }
+            SingleStorageConfig::Sqlite { name, path } => {
+                let path = root.as_ref().join(path);
+                debug!(
+                    r#type = "sqlite",
+                    name,
+                    path = path.to_string_lossy().as_ref(),
+                    create_if_missing
+                );
+                let sqlite = if create_if_missing {
+                    sqlite::SqliteStorage::new(&path, true)?
+                } else {
+                    sqlite::SqliteStorage::load(&path)?
+                };
+                Ok((name, StorageRef::new(sqlite)))
+            }
             SingleStorageConfig::Prefixed {
                 name,
                 prefix,
                 backend,
             } => {
-                let backend = storage
-                    .get_mut(backend)
-                    .ok_or_else(|| ManyError::unknown("Unknown backend storage."))?;
+                let backend = storage.get_mut(&backend).ok_or_else(|| {
+                    ManyError::unknown(format!("Unknown backend storage '{backend}'."))
+                })?;
 
                 Ok((name, backend.cloned_prefixed(prefix.into_bytes())))
             }
+            SingleStorageConfig::Versioned {
+                name,
+                backend,
+                pruning,
+            } => {
+                debug!(r#type = "versioned", name, backend, ?pruning);
+                let backend = storage
+                    .get(&backend)
+                    .ok_or_else(|| {
+                        ManyError::unknown(format!("Unknown backend storage '{backend}'."))
+                    })?
+                    .cloned_prefixed(versioned::namespace(&name));
+                let versioned = versioned::VersionedStorage::new(backend, pruning)?;
+                Ok((name, StorageRef::new_view(versioned)))
+            }
+            SingleStorageConfig::ReadOnly { name, backend } => {
+                debug!(r#type = "read-only", name, backend);
+                let backend = storage
+                    .get(&backend)
+                    .ok_or_else(|| {
+                        ManyError::unknown(format!("Unknown backend storage '{backend}'."))
+                    })?
+                    .clone();
+                let read_only = readonly::ReadOnlyStorage::new(backend);
+                Ok((name, StorageRef::new_view(read_only)))
+            }
+            SingleStorageConfig::Overlay { name, backend } => {
+                debug!(r#type = "overlay", name, backend);
+                let backend = storage
+                    .get(&backend)
+                    .ok_or_else(|| {
+                        ManyError::unknown(format!("Unknown backend storage '{backend}'."))
+                    })?
+                    .clone();
+                let overlay = overlay::OverlayStorage::new(backend);
+                Ok((name, StorageRef::new_view(overlay)))
+            }
             SingleStorageConfig::Null { name } => Ok((name, StorageRef::new(NullKvStore))),
         }
     }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, JsonSchema)]
 #[serde(transparent)]
 pub struct StorageConfig(Vec<SingleStorageConfig>);
 
+impl StorageConfig {
+    pub fn iter(&self) -> impl Iterator<Item = &SingleStorageConfig> {
+        self.0.iter()
+    }
+
+    /// Indices of the storages in the order they should be created, so that
+    /// backends are created before the storages using them. Checks that names
+    /// are unique and that backends exist without cycles.
+    fn order(&self) -> Result<Vec<usize>, ManyError> {
+        let mut by_name = BTreeMap::new();
+        for (i, config) in self.0.iter().enumerate() {
+            let name = config.name();
+            if by_name.insert(name, i).is_some() {
+                return Err(ManyError::unknown(format!(
+                    "Storage '{name}' is declared more than once."
+                )));
+            }
+        }
+        for config in &self.0 {
+            if let Some(backend) = config.backend() {
+                if !by_name.contains_key(backend) {
+                    return Err(ManyError::unknown(format!(
+                        "Storage '{}' uses unknown backend storage '{backend}'.",
+                        config.name()
+                    )));
+                }
+            }
+        }
+
+        // Every storage has at most one backend, so walk the chain of backends
+        // and add them in reverse.
+        let mut order = Vec::with_capacity(self.0.len());
+        let mut done = BTreeSet::new();
+        for config in &self.0 {
+            let mut chain: Vec<&str> = Vec::new();
+            let mut current = Some(config.name());
+            while let Some(name) = current {
+                if done.contains(name) {
+                    break;
+                }
+                if let Some(start) = chain.iter().position(|n| *n == name) {
+                    let mut cycle = chain[start..].to_vec();
+                    cycle.push(name);
+                    return Err(ManyError::unknown(format!(
+                        "Storage '{name}' depends on itself ({}).",
+                        cycle.join(" -> ")
+                    )));
+                }
+                chain.push(name);
+                current = self.0[by_name[name]].backend();
+            }
+            for name in chain.into_iter().rev() {
+                done.insert(name);
+                order.push(by_name[name]);
+            }
+        }
+        Ok(order)
+    }
+
+    /// Take the storages in the order they should be created.
+    pub fn resolve(self) -> Result<Vec<SingleStorageConfig>, ManyError> {
+        let order = self.order()?;
+        let mut configs: Vec<Option<SingleStorageConfig>> = self.0.into_iter().map(Some).collect();
+        Ok(order
+            .into_iter()
+            .filter_map(|i| configs[i].take())
+            .collect())
+    }
+
+    /// Validate all storages without creating them.
+    pub fn check(&self) -> Result<(), ManyError> {
+        self.order()?;
+        for config in &self.0 {
+            config
+                .check()
+                .map_err(|e| ManyError::unknown(format!("Storage '{}': {e}", config.name())))?;
+        }
+        Ok(())
+    }
+}
+
 impl IntoIterator for StorageConfig {
     type Item = SingleStorageConfig;
     type IntoIter = std::vec::IntoIter<SingleStorageConfig>;
@@ -123,15 +367,94 @@ where
     CURRENT_PATH.with(|j| Ok(j.borrow().join(&pb)))
 }
 
-#[derive(Serialize, Deserialize)]
+/// The kind of access a module has to a storage.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize, JsonSchema, Encode, Decode)]
+#[cbor(index_only)]
+pub enum StorageAccess {
+    #[n(0)]
+    Read,
+    #[n(1)]
+    Write,
+    #[n(2)]
+    ReadWrite,
+}
+
+impl StorageAccess {
+    pub fn can_read(&self) -> bool {
+        matches!(self, StorageAccess::Read | StorageAccess::ReadWrite)
+    }
+
+    pub fn can_write(&self) -> bool {
+        matches!(self, StorageAccess::Write | StorageAccess::ReadWrite)
+    }
+}
+
+fn addresses<'de, D>(deserializer: D) -> Result<BTreeSet<Address>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let addresses: Vec<String> = serde::Deserialize::deserialize(deserializer)?;
+    addresses
+        .iter()
+        .map(|a| {
+            Address::from_str(a)
+                .map_err(|_| serde::de::Error::custom(format!("Invalid address: {a}")))
+        })
+        .collect()
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// Resource limits of a module.
+#[derive(Copy, Clone, Debug, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct ModuleLimits {
+    /// Maximum fuel (roughly, WebAssembly instructions) a call to one of the
+    /// module endpoints can use, including the calls it makes to other modules.
+    #[serde(default)]
+    pub fuel: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct SingleModuleConfig {
     pub name: Option<String>,
 
-    #[serde(deserialize_with = "prefix_root")]
+    /// Path of the module. In the map form of `modules`, this is the key.
+    #[serde(default, deserialize_with = "prefix_root")]
     pub path: PathBuf,
 
-    #[serde(deserialize_with = "maybe_load")]
+    /// Passed as JSON to the `init` export of the module on genesis.
+    #[serde(default, deserialize_with = "maybe_load")]
     pub arg: Value,
+
+    /// Disabled modules are not loaded.
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+
+    /// Endpoints exported by the module that are served, by exported name. If
+    /// unset, all of them are served.
+    #[serde(default)]
+    pub endpoints: Option<BTreeSet<String>>,
+
+    /// Endpoints served under another name, from the exported name to the
+    /// served one.
+    #[serde(default)]
+    pub rename: BTreeMap<String, String>,
+
+    #[serde(default)]
+    pub limits: ModuleLimits,
+
+    /// Storages this module is allowed to open, by name. Any storage not listed
+    /// here will be refused to the module.
+    #[serde(default)]
+    pub storages: BTreeMap<String, StorageAccess>,
+
+    /// Hex encoded detached COSE signature of the module. If unset, the
+    /// signature is read from a `.sig` file next to the module, if any.
+    pub signature: Option<String>,
 }
 
 impl SingleModuleConfig {
@@ -144,10 +467,75 @@ impl SingleModuleConfig {
     }
 }
 
-#[derive(Serialize, Deserialize)]
+/// Modules can be listed either as a list of modules with their `path`, or as
+/// a map from paths to module settings.
+#[derive(Serialize)]
 #[repr(transparent)]
 pub struct ModuleConfig(Vec<SingleModuleConfig>);
 
+impl<'de> serde::Deserialize<'de> for ModuleConfig {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        use serde::de::Error;
+
+        let value: Value = serde::Deserialize::deserialize(deserializer)?;
+        let modules = match value {
+            Value::Array(_) => {
+                let modules: Vec<SingleModuleConfig> =
+                    serde_json::from_value(value).map_err(D::Error::custom)?;
+                if let Some(m) = modules.iter().find(|m| m.path.as_os_str().is_empty()) {
+                    return Err(D::Error::custom(format!(
+                        "Module {} has no path.",
+                        m.name.as_deref().unwrap_or("<unnamed>")
+                    )));
+                }
+                modules
+            }
+            Value::Object(map) => map
+                .into_iter()
+                .map(|(path, value)| {
+                    let mut module: SingleModuleConfig =
+                        serde_json::from_value(value).map_err(D::Error::custom)?;
+                    if !module.path.as_os_str().is_empty() {
+                        return Err(D::Error::custom(format!(
+                            "Module {path} has a path in both its key and its settings."
+                        )));
+                    }
+                    module.path = CURRENT_PATH.with(|p| p.borrow().join(&path));
+                    Ok(module)
+                })
+                .collect::<Result<_, _>>()?,
+            _ => return Err(D::Error::custom("Modules must be a list or a map.")),
+        };
+        Ok(ModuleConfig(modules))
+    }
+}
+
+impl JsonSchema for ModuleConfig {
+    fn schema_name() -> String {
+        "ModuleConfig".to_string()
+    }
+
+    fn json_schema(gen: &mut schemars::gen::SchemaGenerator) -> schemars::schema::Schema {
+        schemars::schema::SchemaObject {
+            subschemas: Some(Box::new(schemars::schema::SubschemaValidation {
+                any_of: Some(vec![
+                    gen.subschema_for::<Vec<SingleModuleConfig>>(),
+                    gen.subschema_for::<BTreeMap<String, SingleModuleConfig>>(),
+                ]),
+                ..Default::default()
+            })),
+            ..Default::default()
+        }
+        .into()
+    }
+}
+
+impl ModuleConfig {
+    pub fn iter(&self) -> impl Iterator<Item = &SingleModuleConfig> {
+        self.0.iter()
+    }
+}
+
 impl IntoIterator for ModuleConfig {
     type Item = SingleModuleConfig;
     type IntoIter = std::vec::IntoIter<SingleModuleConfig>;
@@ -157,20 +545,470 @@ impl IntoIterator for ModuleConfig {
     }
 }
 
-#[derive(Serialize, Deserialize)]
+/// A single path or a list of paths.
+fn paths_schema(gen: &mut schemars::gen::SchemaGenerator) -> schemars::schema::Schema {
+    schemars::schema::SchemaObject {
+        subschemas: Some(Box::new(schemars::schema::SubschemaValidation {
+            any_of: Some(vec![
+                gen.subschema_for::<PathBuf>(),
+                gen.subschema_for::<Vec<PathBuf>>(),
+            ]),
+            ..Default::default()
+        })),
+        ..Default::default()
+    }
+    .into()
+}
+
+/// Code signing of modules.
+#[derive(Default, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct SigningConfig {
+    /// Addresses of the publishers whose signatures are accepted.
+    #[serde(default, deserialize_with = "addresses", skip_serializing)]
+    #[schemars(with = "Vec<String>")]
+    pub trusted: BTreeSet<Address>,
+
+    /// Refuse to load modules that are not signed by a trusted publisher.
+    #[serde(default)]
+    pub strict: bool,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct WasmConfig {
+    /// Files merged into this one, relative to it. Those are resolved when
+    /// loading the file, see [`WasmConfig::load`].
+    #[serde(default, skip_serializing)]
+    #[schemars(schema_with = "paths_schema")]
+    pub include: Vec<PathBuf>,
+
     pub init: ModuleConfig,
     pub modules: ModuleConfig,
     pub storages: StorageConfig,
+
+    /// Addresses allowed to call administrative endpoints (e.g. `wasm.reload`).
+    #[serde(default, deserialize_with = "addresses", skip_serializing)]
+    #[schemars(with = "Vec<String>")]
+    pub admins: BTreeSet<Address>,
+
+    /// Name of the storage where modules deployed with `wasm.deploy` are kept.
+    /// If unset, deploying modules is disabled.
+    #[serde(default)]
+    pub deploy_storage: Option<String>,
+
+    #[serde(default)]
+    pub signing: SigningConfig,
 }
 
 impl WasmConfig {
+    /// Validate the whole configuration without opening any database or
+    /// compiling modules. Returns all the errors found.
+    pub fn check(&self) -> Result<(), Vec<String>> {
+        let mut errors = Vec::new();
+        if let Err(e) = self.storages.check() {
+            errors.push(e.to_string());
+        }
+
+        let names: BTreeSet<&str> = self.storages.iter().map(|s| s.name()).collect();
+        for module in self.init.iter().chain(self.modules.iter()) {
+            if !module.enabled {
+                continue;
+            }
+            let name = module.name();
+            if !module.path.is_file() {
+                errors.push(format!(
+                    "Module '{name}': file '{}' does not exist.",
+                    module.path.display()
+                ));
+            }
+            for storage in module.storages.keys() {
+                if !names.contains(storage.as_str()) {
+                    errors.push(format!("Module '{name}': unknown storage '{storage}'."));
+                }
+            }
+        }
+
+        if let Some(deploy) = &self.deploy_storage {
+            if !names.contains(deploy.as_str()) {
+                errors.push(format!("Unknown deploy storage '{deploy}'."));
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Load the configuration at `path`, with its includes and, if the
+    /// [`ENVIRONMENT_VAR`] variable is set, the overlay for that environment.
+    /// Relative paths are relative to the file declaring them.
     pub fn load(path: &Path) -> Result<Self, anyhow::Error> {
         CURRENT_PATH.with(|p| {
             *p.borrow_mut() = path.parent().map(Path::to_path_buf).unwrap_or_default();
         });
 
-        json5::from_str(&std::fs::read_to_string(path)?)
-            .map_err(|e| anyhow!("Could not parse module config: {e}"))
+        let mut value = read_config_value(path, 0)?;
+        if let Ok(env) = std::env::var(ENVIRONMENT_VAR) {
+            let overlay = overlay_path(path, &env);
+            if !overlay.is_file() {
+                return Err(anyhow!(
+                    "Missing configuration overlay for environment '{env}': {}",
+                    overlay.display()
+                ));
+            }
+            merge_values(&mut value, read_config_value(&overlay, 0)?, false);
+        }
+
+        serde_json::from_value(value).map_err(|e| anyhow!("Could not parse module config: {e}"))
+    }
+
+    /// The JSON Schema of the configuration file.
+    pub fn schema() -> schemars::schema::RootSchema {
+        schemars::schema_for!(WasmConfig)
+    }
+}
+
+/// Environment variable naming the environment whose overlay is applied on top
+/// of the configuration, e.g. `prod` for `config.prod.json5`.
+pub const ENVIRONMENT_VAR: &str = "MANY_WASM_ENV";
+
+/// Maximum depth of nested `include`s, which also catches include cycles.
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+/// `config.json5` becomes `config.{env}.json5`.
+fn overlay_path(path: &Path, env: &str) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = path
+        .extension()
+        .map_or(Cow::Borrowed("json5"), |e| e.to_string_lossy());
+    path.with_file_name(format!("{stem}.{env}.{extension}"))
+}
+
+/// Read a JSON5 file, interpolate environment variables in its strings and
+/// keys, make its paths absolute (see [`resolve_paths`]), then merge the files
+/// listed in its `include` field (relative to the file). The file itself has
+/// precedence over its includes.
+fn read_config_value(path: &Path, depth: usize) -> Result<Value, anyhow::Error> {
+    if depth > MAX_INCLUDE_DEPTH {
+        return Err(anyhow!(
+            "Too many nested includes (is there a cycle?): {}",
+            path.display()
+        ));
+    }
+
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| anyhow!("Could not read {}: {e}", path.display()))?;
+    let mut value: Value = json5::from_str(&content)
+        .map_err(|e| anyhow!("Could not parse {}: {e}", path.display()))?;
+    interpolate_value(&mut value).map_err(|e| anyhow!("{}: {e}", path.display()))?;
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+    resolve_paths(&mut value, dir);
+
+    let includes = match value.as_object_mut().and_then(|o| o.remove("include")) {
+        None => return Ok(value),
+        Some(Value::String(s)) => vec![s],
+        Some(Value::Array(a)) => a
+            .into_iter()
+            .map(|v| match v {
+                Value::String(s) => Ok(s),
+                _ => Err(anyhow!("{}: includes must be paths.", path.display())),
+            })
+            .collect::<Result<_, _>>()?,
+        Some(_) => return Err(anyhow!("{}: includes must be paths.", path.display())),
+    };
+
+    let mut result = Value::Object(Default::default());
+    for include in includes {
+        merge_values(
+            &mut result,
+            read_config_value(&dir.join(include), depth + 1)?,
+            true,
+        );
+    }
+    merge_values(&mut result, value, true);
+    Ok(result)
+}
+
+/// Make the paths of modules (and their `arg` files) and storages relative to
+/// `dir`, the directory of the file declaring them, instead of the directory
+/// of the main configuration file.
+fn resolve_paths(value: &mut Value, dir: &Path) {
+    let join = |path: &str| dir.join(path).to_string_lossy().into_owned();
+    let resolve = |value: &mut Value, field: &str| {
+        if let Some(Value::String(path)) = value.get_mut(field) {
+            *path = join(path);
+        }
+    };
+
+    let config = match value.as_object_mut() {
+        Some(config) => config,
+        None => return,
+    };
+    for key in ["init", "modules"] {
+        match config.get_mut(key) {
+            Some(Value::Array(modules)) => {
+                for module in modules.iter_mut() {
+                    resolve(module, "path");
+                    resolve(module, "arg");
+                }
+            }
+            Some(Value::Object(modules)) => {
+                *modules = std::mem::take(modules)
+                    .into_iter()
+                    .map(|(path, mut module)| {
+                        resolve(&mut module, "arg");
+                        (join(&path), module)
+                    })
+                    .collect();
+            }
+            _ => {}
+        }
+    }
+    if let Some(Value::Array(storages)) = config.get_mut("storages") {
+        for storage in storages.iter_mut() {
+            resolve(storage, "path");
+        }
+    }
+}
+
+/// Merge `from` into `into`. Objects are merged recursively, arrays are
+/// concatenated if `concat` is set, and anything else is replaced by `from`.
+fn merge_values(into: &mut Value, from: Value, concat: bool) {
+    match (into, from) {
+        (Value::Object(into), Value::Object(from)) => {
+            for (k, v) in from {
+                match into.get_mut(&k) {
+                    Some(existing) => merge_values(existing, v, concat),
+                    None => {
+                        into.insert(k, v);
+                    }
+                }
+            }
+        }
+        (Value::Array(into), Value::Array(from)) if concat => into.extend(from),
+        (into, from) => *into = from,
+    }
+}
+
+fn interpolate_value(value: &mut Value) -> Result<(), anyhow::Error> {
+    match value {
+        Value::String(s) => *s = interpolate(s)?,
+        Value::Array(a) => a.iter_mut().try_for_each(interpolate_value)?,
+        Value::Object(o) => {
+            *o = std::mem::take(o)
+                .into_iter()
+                .map(|(k, mut v)| {
+                    interpolate_value(&mut v)?;
+                    Ok((interpolate(&k)?, v))
+                })
+                .collect::<Result<_, anyhow::Error>>()?;
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Replace `${NAME}` by the value of the environment variable `NAME`, or
+/// `${NAME:-default}` by `default` if it is unset. `$${` is a literal `${`.
+fn interpolate(s: &str) -> Result<String, anyhow::Error> {
+    let mut result = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(start) = rest.find("${") {
+        if rest[..start].ends_with('$') {
+            result.push_str(&rest[..start - 1]);
+            result.push_str("${");
+            rest = &rest[start + 2..];
+            continue;
+        }
+        result.push_str(&rest[..start]);
+
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| anyhow!("Unterminated variable in '{s}'."))?;
+        let expr = &rest[start + 2..start + end];
+        let (name, default) = match expr.split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None => (expr, None),
+        };
+        match (std::env::var(name), default) {
+            (Ok(v), _) => result.push_str(&v),
+            (Err(_), Some(default)) => result.push_str(default),
+            (Err(_), None) => return Err(anyhow!("Environment variable '{name}' is not set.")),
+        }
+        rest = &rest[start + end + 1..];
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn storages(value: Value) -> StorageConfig {
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn names(config: StorageConfig) -> Vec<String> {
+        config
+            .resolve()
+            .unwrap()
+            .iter()
+            .map(|s| s.name().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn storage_order() {
+        // Backends declared after the storages using them are created first.
+        let config = storages(json!([
+            { "type": "Prefixed", "name": "balances", "prefix": "b/", "backend": "ledger" },
+            { "type": "Overlay", "name": "ledger", "backend": "base" },
+            { "type": "Memory", "name": "other" },
+            { "type": "Merk", "name": "base", "path": "base.db" },
+        ]));
+        assert!(config.check().is_ok());
+        assert_eq!(names(config), ["base", "ledger", "balances", "other"]);
+    }
+
+    #[test]
+    fn storage_cycle() {
+        let config = storages(json!([
+            { "type": "Memory", "name": "memory" },
+            { "type": "Overlay", "name": "a", "backend": "b" },
+            { "type": "ReadOnly", "name": "b", "backend": "a" },
+        ]));
+        let err = config.check().unwrap_err().to_string();
+        assert!(err.contains("a -> b -> a"), "{err}");
+        assert!(config.resolve().is_err());
+
+        let config = storages(json!([
+            { "type": "Prefixed", "name": "a", "prefix": "", "backend": "a" },
+        ]));
+        assert!(config.resolve().is_err());
+    }
+
+    #[test]
+    fn storage_missing_backend() {
+        let config = storages(json!([
+            { "type": "Memory", "name": "memory" },
+            { "type": "Versioned", "name": "versioned", "backend": "missing" },
+        ]));
+        let err = config.check().unwrap_err().to_string();
+        assert!(err.contains("'missing'"), "{err}");
+        assert!(config.resolve().is_err());
+    }
+
+    #[test]
+    fn storage_duplicate_names() {
+        let config = storages(json!([
+            { "type": "Memory", "name": "a" },
+            { "type": "Null", "name": "a" },
+        ]));
+        let err = config.check().unwrap_err().to_string();
+        assert!(err.contains("more than once"), "{err}");
+        assert!(config.resolve().is_err());
+    }
+
+    #[test]
+    fn storage_check_values() {
+        let config = storages(json!([
+            { "type": "Memory", "name": "a", "values": { "00": "zz" } },
+        ]));
+        assert!(config.check().is_err());
+        assert!(config.resolve().is_ok());
+    }
+
+    #[test]
+    fn merge() {
+        let mut value = json!({ "a": { "b": 1, "c": [1] }, "d": [1], "e": 1 });
+        let from = json!({ "a": { "b": 2, "c": [2], "f": 3 }, "d": 2, "e": [1] });
+        let mut replaced = value.clone();
+
+        merge_values(&mut value, from.clone(), true);
+        assert_eq!(
+            value,
+            json!({ "a": { "b": 2, "c": [1, 2], "f": 3 }, "d": 2, "e": [1] })
+        );
+
+        merge_values(&mut replaced, from, false);
+        assert_eq!(
+            replaced,
+            json!({ "a": { "b": 2, "c": [2], "f": 3 }, "d": 2, "e": [1] })
+        );
+    }
+
+    #[test]
+    fn interpolation() {
+        std::env::set_var("MANY_WASM_TEST_VAR", "value");
+        std::env::remove_var("MANY_WASM_TEST_UNSET");
+
+        assert_eq!(interpolate("a${MANY_WASM_TEST_VAR}b").unwrap(), "avalueb");
+        assert_eq!(
+            interpolate("${MANY_WASM_TEST_UNSET:-default}").unwrap(),
+            "default"
+        );
+        assert_eq!(interpolate("${MANY_WASM_TEST_VAR:-}").unwrap(), "value");
+        assert_eq!(
+            interpolate("$${MANY_WASM_TEST_VAR} $$").unwrap(),
+            "${MANY_WASM_TEST_VAR} $$"
+        );
+        assert!(interpolate("${MANY_WASM_TEST_UNSET}").is_err());
+        assert!(interpolate("${MANY_WASM_TEST_VAR").is_err());
+
+        let mut value = json!({ "${MANY_WASM_TEST_VAR}.wasm": ["${MANY_WASM_TEST_VAR}"] });
+        interpolate_value(&mut value).unwrap();
+        assert_eq!(value, json!({ "value.wasm": ["value"] }));
+    }
+
+    #[test]
+    fn nested_include() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        std::fs::create_dir(root.join("sub")).unwrap();
+        std::fs::write(
+            root.join("config.json5"),
+            r#"{
+                include: "sub/modules.json5",
+                modules: { "a.wasm": { arg: { a: 1 } } },
+            }"#,
+        )
+        .unwrap();
+        std::fs::write(
+            root.join("sub/modules.json5"),
+            r#"{
+                include: ["storages.json5"],
+                modules: { "b.wasm": {} },
+                init: [{ path: "init.wasm", arg: "init.json" }],
+            }"#,
+        )
+        .unwrap();
+        std::fs::write(
+            root.join("sub/storages.json5"),
+            r#"{ storages: [{ type: "Merk", name: "m", path: "merk.db" }] }"#,
+        )
+        .unwrap();
+
+        let value = read_config_value(&root.join("config.json5"), 0).unwrap();
+        let path = |p: &str| root.join(p).to_string_lossy().into_owned();
+        let mut modules = serde_json::Map::new();
+        modules.insert(path("a.wasm"), json!({ "arg": { "a": 1 } }));
+        modules.insert(path("sub/b.wasm"), json!({}));
+        assert_eq!(value["modules"], Value::Object(modules));
+        assert_eq!(value["init"][0]["path"], path("sub/init.wasm"));
+        assert_eq!(value["init"][0]["arg"], path("sub/init.json"));
+        assert_eq!(value["storages"][0]["path"], path("sub/merk.db"));
+    }
+
+    #[test]
+    fn include_cycle() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a.json5"), r#"{ include: "b.json5" }"#).unwrap();
+        std::fs::write(dir.path().join("b.json5"), r#"{ include: "a.json5" }"#).unwrap();
+        assert!(read_config_value(&dir.path().join("a.json5"), 0).is_err());
     }
 }
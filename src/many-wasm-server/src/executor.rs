@@ -1,26 +1,134 @@
+use crate::config::WasmConfig;
+use crate::wasm_engine::deploy::DeployArgs;
 use crate::wasm_engine::WasmEngine;
 use async_trait::async_trait;
 use coset::CoseSign1;
+use many_error::ManyError;
 use many_identity::verifiers::AnonymousVerifier;
-use many_identity::Identity;
+use many_identity::{Address, Identity};
 use many_identity_dsa::CoseKeyVerifier;
+use many_modules::base::{Endpoints, StatusBuilder};
+use many_modules::EmptyReturn;
 use many_protocol::{
-    decode_request_from_cose_sign1, encode_cose_sign1_from_response, ResponseMessage,
+    decode_request_from_cose_sign1, encode_cose_sign1_from_response, RequestMessage,
+    ResponseMessage,
 };
 use many_server::transport::LowLevelManyRequestHandler;
+use many_types::attributes::Attribute;
+use std::collections::BTreeSet;
 use std::fmt::{Debug, Formatter};
+use std::path::PathBuf;
 use std::sync::{Arc, RwLock};
+use tracing::warn;
+
+/// Endpoints of the base module, answered natively by the executor.
+const BASE_ENDPOINTS: [&str; 3] = ["status", "heartbeat", "endpoints"];
+
+/// Administrative endpoints, also answered by the executor.
+const ADMIN_ENDPOINTS: [&str; 2] = ["wasm.reload", "wasm.deploy"];
 
 pub struct WasmExecutor<I: Identity> {
     engine: RwLock<WasmEngine>,
     identity: Arc<I>,
+    config_path: PathBuf,
+    admins: BTreeSet<Address>,
 }
 
 impl<I: Identity> WasmExecutor<I> {
-    pub fn new(engine: WasmEngine, identity: I) -> Self {
+    pub fn new(
+        engine: WasmEngine,
+        identity: I,
+        config_path: PathBuf,
+        admins: BTreeSet<Address>,
+    ) -> Self {
         Self {
             engine: RwLock::new(engine),
             identity: Arc::new(identity),
+            config_path,
+            admins,
+        }
+    }
+
+    fn check_admin(&self, request: &RequestMessage) -> Result<(), ManyError> {
+        match request.from {
+            Some(from) if self.admins.contains(&from) => Ok(()),
+            from => {
+                warn!(
+                    "Refused admin endpoint {} from {}",
+                    request.method,
+                    from.unwrap_or_default()
+                );
+                Err(ManyError::invalid_from_identity())
+            }
+        }
+    }
+
+    /// The status of the server, with the attributes of all loaded modules.
+    fn status(&self, engine: &WasmEngine) -> Result<Vec<u8>, ManyError> {
+        let mut attributes = BTreeSet::from([0]);
+        attributes.extend(engine.modules().attributes());
+
+        let mut builder = StatusBuilder::default();
+        builder
+            .name("many-wasm-server".to_string())
+            .identity(self.identity.address())
+            .attributes(attributes.into_iter().map(Attribute::id).collect())
+            .server_version(env!("CARGO_PKG_VERSION").to_string());
+        if let Some(public_key) = self.identity.public_key() {
+            builder.public_key(public_key);
+        }
+
+        let status = builder.build().map_err(ManyError::unknown)?;
+        minicbor::to_vec(status).map_err(ManyError::serialization_error)
+    }
+
+    /// All endpoints answered by this server, including the base module and
+    /// administrative endpoints.
+    fn endpoints(&self, engine: &WasmEngine) -> Result<Vec<u8>, ManyError> {
+        let mut endpoints: BTreeSet<String> = BASE_ENDPOINTS
+            .into_iter()
+            .chain(ADMIN_ENDPOINTS)
+            .map(String::from)
+            .collect();
+        endpoints.extend(engine.modules().endpoints().cloned());
+        minicbor::to_vec(Endpoints(endpoints)).map_err(ManyError::serialization_error)
+    }
+
+    /// Deploy a module from the request payload. Returns the hash of the module.
+    fn deploy(
+        &self,
+        engine: &mut WasmEngine,
+        request: &RequestMessage,
+    ) -> Result<Vec<u8>, ManyError> {
+        self.check_admin(request)?;
+
+        let args: DeployArgs =
+            minicbor::decode(&request.data).map_err(ManyError::deserialization_error)?;
+        let hash = engine.deploy(args)?;
+        minicbor::to_vec(minicbor::bytes::ByteVec::from(hash))
+            .map_err(ManyError::serialization_error)
+    }
+
+    /// Re-read the module configuration and swap the modules in the engine.
+    /// Returns the list of loaded module names.
+    fn reload(
+        &self,
+        engine: &mut WasmEngine,
+        request: &RequestMessage,
+    ) -> Result<Vec<u8>, ManyError> {
+        self.check_admin(request)?;
+
+        let config = WasmConfig::load(&self.config_path).map_err(ManyError::unknown)?;
+        match engine.reload_module_config(config.modules) {
+            Ok(names) => minicbor::to_vec(names).map_err(ManyError::serialization_error),
+            Err(errors) => {
+                let mut err = ManyError::unknown("Could not reload modules.");
+                for (name, e) in errors {
+                    warn!(module = name.as_str(), "Could not reload module: {e}");
+                    err.add_argument(name, e);
+                }
+                Err(err)
+            }
         }
     }
 }
@@ -45,7 +153,14 @@ impl<'a, I: Identity + std::marker::Sync> LowLevelManyRequestHandler for WasmExe
             .write()
             .map_err(|_| String::from("Lock is poisoned."))?;
 
-        let data = engine.call_endpoint(&request);
+        let data = match request.method.as_str() {
+            "status" => self.status(&engine),
+            "heartbeat" => minicbor::to_vec(EmptyReturn).map_err(ManyError::serialization_error),
+            "endpoints" => self.endpoints(&engine),
+            "wasm.reload" => self.reload(&mut engine, &request),
+            "wasm.deploy" => self.deploy(&mut engine, &request),
+            _ => engine.call_endpoint(&request),
+        };
 
         let response = ResponseMessage::from_request(&request, &self.identity.address(), data);
         encode_cose_sign1_from_response(response, &self.identity).map_err(|e| e.to_string())
@@ -1,44 +1,462 @@
 extern crate core;
 
-use crate::config::WasmConfig;
-use crate::storage::StorageLibrary;
-use clap::Parser;
+use crate::config::{ModuleConfig, SigningConfig, WasmConfig};
+use crate::storage::{KvStore, StorageLibrary};
+use crate::wasm_engine::WasmEngine;
+use anyhow::anyhow;
+use clap::{Args, Parser, Subcommand};
+use many_identity::{Address, Identity};
+use many_identity_dsa::CoseKeyIdentity;
+use many_protocol::RequestMessage;
 use many_server::transport::http::HttpServer;
+use std::collections::BTreeMap;
 use std::net::SocketAddr;
 use std::path::{Path, PathBuf};
 use tracing::info;
 use tracing::level_filters::LevelFilter;
 
 mod abi;
+mod cbor;
 mod config;
 mod executor;
+mod signature;
+mod snapshot;
 mod storage;
 mod wasm_engine;
 
-#[derive(Debug, Parser)]
-struct Opts {
+#[derive(Debug, Args)]
+struct ConfigArgs {
     /// Path to the configuration file.
     config: PathBuf,
+}
 
-    /// Whether to create databases.
-    #[clap(long, default_value = "false")]
-    init: bool,
+impl ConfigArgs {
+    /// Load the configuration. Returns it with its absolute path and directory.
+    fn load(&self) -> Result<(WasmConfig, PathBuf, PathBuf), anyhow::Error> {
+        let config_dir = self.config.parent().unwrap_or_else(|| Path::new(""));
+        let config_dir = std::env::current_dir()?.join(config_dir);
+        let config_path = config_dir.join(self.config.file_name().unwrap_or_default());
+        let config = WasmConfig::load(&config_path)?;
+        Ok((config, config_path, config_dir))
+    }
+}
 
-    /// Path to the PEM file for the identity.
+#[derive(Debug, Args)]
+struct IdentityArgs {
+    /// Path to the PEM file for the identity. Anonymous if unset, which is
+    /// only accepted by `call`.
     #[clap(long)]
-    pem: PathBuf,
+    pem: Option<PathBuf>,
+}
 
-    /// Binding socket.
-    #[clap(long)]
-    bind: SocketAddr,
+impl IdentityArgs {
+    fn load(&self) -> Result<CoseKeyIdentity, anyhow::Error> {
+        let path = self
+            .pem
+            .as_ref()
+            .ok_or_else(|| anyhow!("An identity is required, use --pem."))?;
+        let pem =
+            std::fs::read_to_string(path).map_err(|e| anyhow!("Could not read PEM file: {e}"))?;
+        CoseKeyIdentity::from_pem(pem).map_err(|e| anyhow!("Could not parse PEM file: {e}"))
+    }
+
+    /// The address of the identity, or anonymous if there is none.
+    fn address(&self) -> Result<Address, anyhow::Error> {
+        match self.pem {
+            Some(_) => Ok(self.load()?.address()),
+            None => Ok(Address::anonymous()),
+        }
+    }
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Serve the modules over HTTP.
+    Serve {
+        #[clap(flatten)]
+        config: ConfigArgs,
+
+        #[clap(flatten)]
+        identity: IdentityArgs,
+
+        /// Binding socket.
+        #[clap(long)]
+        bind: SocketAddr,
+
+        /// Serve the state of versioned storages as of this past height. All
+        /// endpoints that write to those storages will fail.
+        #[clap(long)]
+        height: Option<u64>,
+    },
+
+    /// Create the storages and run the init modules, then exit.
+    Genesis {
+        #[clap(flatten)]
+        config: ConfigArgs,
+
+        #[clap(flatten)]
+        identity: IdentityArgs,
+
+        /// Delete existing databases instead of refusing to run.
+        #[clap(long)]
+        force: bool,
+    },
+
+    /// Call an endpoint locally, without a server, and print its response.
+    Call {
+        #[clap(flatten)]
+        config: ConfigArgs,
+
+        #[clap(flatten)]
+        identity: IdentityArgs,
+
+        /// Name of the endpoint.
+        endpoint: String,
+
+        /// Address of the sender. Anonymous if unset.
+        #[clap(long)]
+        from: Option<Address>,
+
+        /// Payload, either hex encoded CBOR or JSON. JSON is converted to
+        /// CBOR, with integer object keys encoded as integers. It is read as
+        /// JSON if it starts with `{`, `[` or `"`, and as hex otherwise.
+        #[clap(long)]
+        data: Option<String>,
+
+        /// Always read the payload as JSON, e.g. for a number.
+        #[clap(long)]
+        json: bool,
+
+        /// Do not commit the storages, so the call has no effect.
+        #[clap(long)]
+        dry_run: bool,
+    },
 
+    /// List the endpoints and imports of a module file, and check that all
+    /// imports can be linked.
+    InspectModule {
+        /// Path to the module.
+        path: PathBuf,
+    },
+
+    /// Print all keys and values of a storage, hex encoded.
+    DumpStorage {
+        #[clap(flatten)]
+        config: ConfigArgs,
+
+        /// Name of the storage.
+        name: String,
+    },
+
+    /// Validate the configuration without opening databases.
+    CheckConfig {
+        #[clap(flatten)]
+        config: ConfigArgs,
+    },
+
+    /// Export all root storages to a snapshot file.
+    Export {
+        #[clap(flatten)]
+        config: ConfigArgs,
+
+        /// Path of the snapshot file to write.
+        output: PathBuf,
+    },
+
+    /// Create all storages and restore them from a snapshot file.
+    Import {
+        #[clap(flatten)]
+        config: ConfigArgs,
+
+        /// Path of the snapshot file to read.
+        input: PathBuf,
+
+        /// Delete existing databases instead of refusing to run.
+        #[clap(long)]
+        force: bool,
+    },
+
+    /// Print the JSON Schema of the configuration file.
+    Schema,
+}
+
+#[derive(Debug, Parser)]
+struct Opts {
     /// Increase output logging verbosity to DEBUG level.
-    #[clap(short, long, action = clap::ArgAction::Count)]
+    #[clap(short, long, action = clap::ArgAction::Count, global = true)]
     verbose: u8,
 
     /// Suppress all output logging. Can be used multiple times to suppress more.
-    #[clap(short, long, action = clap::ArgAction::Count)]
+    #[clap(short, long, action = clap::ArgAction::Count, global = true)]
     quiet: u8,
+
+    #[clap(subcommand)]
+    command: Command,
+}
+
+/// Create the engine with the configured modules.
+fn create_engine(
+    storage: StorageLibrary,
+    address: Address,
+    signing: SigningConfig,
+    modules: ModuleConfig,
+    deploy_storage: Option<String>,
+) -> Result<WasmEngine, anyhow::Error> {
+    let mut engine = WasmEngine::new(storage, address)?;
+    engine.set_module_verifier(signature::ModuleVerifier::new(
+        signing.trusted,
+        signing.strict,
+    ));
+    engine.add_module_config(modules)?;
+    engine.set_deploy_storage(deploy_storage);
+    Ok(engine)
+}
+
+async fn serve(
+    config: ConfigArgs,
+    identity: IdentityArgs,
+    bind: SocketAddr,
+    height: Option<u64>,
+) -> Result<(), anyhow::Error> {
+    let (config, config_path, config_dir) = config.load()?;
+    let key = identity.load()?;
+
+    let mut storage = StorageLibrary::create(config.storages, &config_dir, false)
+        .map_err(|e| anyhow!("Could not open storage: {e}"))?;
+    if let Some(height) = height {
+        storage
+            .checkout(height)
+            .map_err(|e| anyhow!("Could not checkout storage height: {e}"))?;
+    }
+
+    let mut engine = create_engine(
+        storage,
+        key.address(),
+        config.signing,
+        config.modules,
+        config.deploy_storage,
+    )?;
+    engine.load_deployed()?;
+    // Storages cannot be written to at a past height.
+    if height.is_none() {
+        engine
+            .migrate_modules()
+            .map_err(|e| anyhow!("Could not migrate modules: {e}"))?;
+    }
+    let executor = executor::WasmExecutor::new(engine, key, config_path, config.admins);
+    HttpServer::new(executor)
+        .bind(bind)
+        .await
+        .map_err(|e| anyhow!("{e}"))
+}
+
+/// Refuse to overwrite the databases of the storages that already exist.
+fn check_no_databases(config: &WasmConfig, config_dir: &Path) -> Result<(), anyhow::Error> {
+    let existing: Vec<String> = config
+        .storages
+        .iter()
+        .filter_map(|s| s.path())
+        .map(|p| config_dir.join(p))
+        .filter(|p| p.exists())
+        .map(|p| p.display().to_string())
+        .collect();
+    if !existing.is_empty() {
+        return Err(anyhow!(
+            "Databases already exist (use --force to overwrite): {}",
+            existing.join(", ")
+        ));
+    }
+    Ok(())
+}
+
+fn genesis(config: ConfigArgs, identity: IdentityArgs, force: bool) -> Result<(), anyhow::Error> {
+    let (config, _, config_dir) = config.load()?;
+    let key = identity.load()?;
+
+    if !force {
+        check_no_databases(&config, &config_dir)?;
+    }
+
+    let storage = StorageLibrary::create(config.storages, &config_dir, true)
+        .map_err(|e| anyhow!("Could not create storage: {e}"))?;
+    let mut engine = create_engine(
+        storage,
+        key.address(),
+        config.signing,
+        config.modules,
+        config.deploy_storage,
+    )?;
+    engine.init(config.init)?;
+    for (name, store) in engine.storage().roots() {
+        let hash = store.hash().map_err(|e| anyhow!("{e}"))?;
+        info!(storage = name, hash = %hex::encode(hash), "Storage hash.");
+    }
+    info!("Genesis done.");
+    Ok(())
+}
+
+fn call(
+    config: ConfigArgs,
+    identity: IdentityArgs,
+    endpoint: String,
+    from: Option<Address>,
+    data: Option<String>,
+    json: bool,
+    dry_run: bool,
+) -> Result<(), anyhow::Error> {
+    let (config, _, config_dir) = config.load()?;
+    let address = identity.address()?;
+    let data = match data {
+        None => Vec::new(),
+        Some(data) if json || data.trim_start().starts_with(['{', '[', '"']) => {
+            let value: serde_json::Value =
+                serde_json::from_str(&data).map_err(|e| anyhow!("Data is not JSON: {e}"))?;
+            cbor::from_json(&value)?
+        }
+        Some(data) => {
+            hex::decode(&data).map_err(|e| anyhow!("Data is not hex (use --json for JSON): {e}"))?
+        }
+    };
+
+    let storage = StorageLibrary::create(config.storages, &config_dir, false)
+        .map_err(|e| anyhow!("Could not open storage: {e}"))?;
+    let mut engine = create_engine(
+        storage,
+        address,
+        config.signing,
+        config.modules,
+        config.deploy_storage,
+    )?;
+    engine.load_deployed()?;
+
+    let request = RequestMessage {
+        from,
+        to: address,
+        method: endpoint,
+        data,
+        ..Default::default()
+    };
+    let result = if dry_run {
+        engine.call_endpoint_uncommitted(&request)
+    } else {
+        engine.call_endpoint(&request)
+    };
+    let response = result.map_err(|e| anyhow!("{e}"))?;
+
+    // Fall back to hex if the response is not valid CBOR.
+    match cbor::to_json(&response) {
+        Ok(value) => println!("{}", serde_json::to_string_pretty(&value)?),
+        Err(_) => println!("{}", hex::encode(response)),
+    }
+    Ok(())
+}
+
+fn inspect_module(path: PathBuf) -> Result<(), anyhow::Error> {
+    let bytes =
+        std::fs::read(&path).map_err(|e| anyhow!("Could not read {}: {e}", path.display()))?;
+    let mut engine = WasmEngine::new(StorageLibrary::new(), Address::anonymous())?;
+    let module = engine.compile(&bytes)?;
+
+    println!("Endpoints:");
+    for endpoint in wasm_engine::exported_endpoints(&module) {
+        println!("  {endpoint}");
+    }
+    let init = module.get_export("init").is_some();
+    println!("Init: {}", if init { "yes" } else { "no" });
+
+    let mut imports: BTreeMap<&str, Vec<_>> = BTreeMap::new();
+    for import in module.imports() {
+        imports.entry(import.module()).or_default().push(import);
+    }
+
+    println!("Imports:");
+    let mut missing = 0;
+    for (name, imports) in imports {
+        println!("  {name}:");
+        for import in imports {
+            match engine.check_import(&import) {
+                Ok(()) => println!("    {}", import.name()),
+                Err(e) => {
+                    missing += 1;
+                    println!("    {} (MISSING: {e})", import.name());
+                }
+            }
+        }
+    }
+
+    if missing > 0 {
+        return Err(anyhow!("{missing} import(s) cannot be linked."));
+    }
+    Ok(())
+}
+
+fn dump_storage(config: ConfigArgs, name: String) -> Result<(), anyhow::Error> {
+    let (config, _, config_dir) = config.load()?;
+    let storage = StorageLibrary::create(config.storages, &config_dir, false)
+        .map_err(|e| anyhow!("Could not open storage: {e}"))?;
+    let store = storage
+        .get(&name)
+        .ok_or_else(|| anyhow!("Unknown storage '{name}'."))?;
+
+    let mut next = if store.contains(&[]) {
+        Some(Vec::new())
+    } else {
+        store.next_key(&[]).map_err(|e| anyhow!("{e}"))?
+    };
+    while let Some(key) = next {
+        let value = store.get(&key).map_err(|e| anyhow!("{e}"))?;
+        println!(
+            "{} = {}",
+            hex::encode(&key),
+            hex::encode(value.unwrap_or_default())
+        );
+        next = store.next_key(&key).map_err(|e| anyhow!("{e}"))?;
+    }
+    Ok(())
+}
+
+fn check_config(config: ConfigArgs) -> Result<(), anyhow::Error> {
+    let (config, _, _) = config.load()?;
+    match config.check() {
+        Ok(()) => {
+            println!("Configuration is valid.");
+            Ok(())
+        }
+        Err(errors) => {
+            for e in &errors {
+                eprintln!("{e}");
+            }
+            Err(anyhow!(
+                "Found {} error(s) in the configuration.",
+                errors.len()
+            ))
+        }
+    }
+}
+
+fn export(config: ConfigArgs, output: PathBuf) -> Result<(), anyhow::Error> {
+    let (config, _, config_dir) = config.load()?;
+    let storage = StorageLibrary::create(config.storages, &config_dir, false)
+        .map_err(|e| anyhow!("Could not open storage: {e}"))?;
+    let file = std::fs::File::create(output)?;
+    snapshot::export(&storage, std::io::BufWriter::new(file))
+}
+
+fn import(config: ConfigArgs, input: PathBuf, force: bool) -> Result<(), anyhow::Error> {
+    let (config, _, config_dir) = config.load()?;
+    if !force {
+        check_no_databases(&config, &config_dir)?;
+    }
+
+    let mut storage = StorageLibrary::create(config.storages, &config_dir, true)
+        .map_err(|e| anyhow!("Could not create storage: {e}"))?;
+    let file = std::fs::File::open(input)?;
+    snapshot::import(&mut storage, std::io::BufReader::new(file))
+}
+
+fn schema() -> Result<(), anyhow::Error> {
+    println!("{}", serde_json::to_string_pretty(&WasmConfig::schema())?);
+    Ok(())
 }
 
 #[tokio::main(flavor = "current_thread")]
@@ -54,35 +472,47 @@ async fn main() {
         x if x <= 0 => LevelFilter::OFF,
         _ => unreachable!(),
     };
-    tracing_subscriber::fmt().with_max_level(log_level).init();
+    tracing_subscriber::fmt()
+        .with_max_level(log_level)
+        .with_writer(std::io::stderr)
+        .init();
     info!("opts = {:?}", opts);
 
-    let config_dir = opts.config.parent().unwrap_or_else(|| Path::new(""));
-    let config_dir = std::env::current_dir().unwrap().join(config_dir);
-
-    let config: WasmConfig = WasmConfig::load(&config_dir.join(&opts.config)).unwrap();
-    let storage = StorageLibrary::create(config.storages, &config_dir, opts.init)
-        .expect("Could not create storage.");
-
-    let key = many_identity_dsa::CoseKeyIdentity::from_pem(
-        std::fs::read_to_string(opts.pem).expect("Could not read PEM file."),
-    )
-    .expect("Could not parse PEM file.");
-
-    let mut engine = wasm_engine::WasmEngine::new(storage).expect("Could not create engine.");
-    engine
-        .add_module_config(config.modules)
-        .expect("Could not load modules.");
+    let result = match opts.command {
+        Command::Serve {
+            config,
+            identity,
+            bind,
+            height,
+        } => serve(config, identity, bind, height).await,
+        Command::Genesis {
+            config,
+            identity,
+            force,
+        } => genesis(config, identity, force),
+        Command::Call {
+            config,
+            identity,
+            endpoint,
+            from,
+            data,
+            json,
+            dry_run,
+        } => call(config, identity, endpoint, from, data, json, dry_run),
+        Command::InspectModule { path } => inspect_module(path),
+        Command::DumpStorage { config, name } => dump_storage(config, name),
+        Command::CheckConfig { config } => check_config(config),
+        Command::Export { config, output } => export(config, output),
+        Command::Import {
+            config,
+            input,
+            force,
+        } => import(config, input, force),
+        Command::Schema => schema(),
+    };
 
-    // Check if we need to init.
-    if opts.init {
-        engine
-            .init(config.init)
-            .expect("Could not initialize engine.");
+    if let Err(e) = result {
+        eprintln!("{e}");
+        std::process::exit(1);
     }
-
-    let executor = executor::WasmExecutor::new(engine, key);
-    let server = HttpServer::new(executor);
-
-    server.bind(opts.bind).await.unwrap();
 }
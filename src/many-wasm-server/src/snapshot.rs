@@ -0,0 +1,399 @@
+//! Snapshots of all root storages of a `StorageLibrary`.
+//!
+//! A snapshot is a stream of frames, each one a big endian `u32` length
+//! followed by a CBOR encoded [`Frame`]. It starts with a header, then for
+//! every root storage its name, all its key-value pairs in key order and the
+//! hash of those pairs, and ends with the SHA3 of all the bytes that came
+//! before the last frame.
+//!
+//! The hash of a storage is computed from its content, like `MemoryStorage`,
+//! and not by the storage itself. Merk's root hash depends on the shape of its
+//! tree, so it cannot be compared after restoring the entries.
+use crate::storage::memory::EntriesHasher;
+use crate::storage::{KvStore, StorageLibrary};
+use anyhow::{anyhow, bail};
+use minicbor::bytes::ByteVec;
+use minicbor::{Decode, Encode};
+use sha3::{Digest, Sha3_256};
+use std::io::{ErrorKind, Read, Write};
+use tracing::info;
+
+const VERSION: u32 = 2;
+
+/// Frames are limited in size to avoid allocating arbitrary amounts of memory
+/// when reading a corrupted file.
+const MAX_FRAME_SIZE: u32 = 256 * 1024 * 1024;
+
+#[derive(Debug, Encode, Decode)]
+enum Frame {
+    #[n(0)]
+    Header {
+        #[n(0)]
+        version: u32,
+    },
+    #[n(1)]
+    Storage {
+        #[n(0)]
+        name: String,
+    },
+    #[n(2)]
+    Entry {
+        #[n(0)]
+        key: ByteVec,
+        #[n(1)]
+        value: ByteVec,
+    },
+    #[n(3)]
+    End {
+        #[n(0)]
+        checksum: ByteVec,
+    },
+    #[n(4)]
+    StorageEnd {
+        #[n(0)]
+        hash: ByteVec,
+    },
+}
+
+struct FrameWriter<W: Write> {
+    inner: W,
+    hasher: Sha3_256,
+}
+
+impl<W: Write> FrameWriter<W> {
+    fn write(&mut self, frame: &Frame) -> Result<(), anyhow::Error> {
+        let bytes = minicbor::to_vec(frame).map_err(|e| anyhow!("{e}"))?;
+        let len = (bytes.len() as u32).to_be_bytes();
+        self.hasher.update(len);
+        self.hasher.update(&bytes);
+        self.inner.write_all(&len)?;
+        self.inner.write_all(&bytes)?;
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<W, anyhow::Error> {
+        let checksum = self.hasher.clone().finalize().to_vec();
+        self.write(&Frame::End {
+            checksum: checksum.into(),
+        })?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+struct FrameReader<R: Read> {
+    inner: R,
+    hasher: Sha3_256,
+}
+
+impl<R: Read> FrameReader<R> {
+    /// Read the next frame, returning it with the checksum of all bytes read
+    /// before it.
+    fn read(&mut self) -> Result<Option<(Frame, Vec<u8>)>, anyhow::Error> {
+        let mut len = [0u8; 4];
+        match self.inner.read_exact(&mut len) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+        let size = u32::from_be_bytes(len);
+        if size > MAX_FRAME_SIZE {
+            bail!("Frame too large ({size} bytes).");
+        }
+
+        let mut bytes = vec![0u8; size as usize];
+        self.inner.read_exact(&mut bytes)?;
+        let checksum = self.hasher.clone().finalize().to_vec();
+        self.hasher.update(len);
+        self.hasher.update(&bytes);
+
+        let frame = minicbor::decode(&bytes).map_err(|e| anyhow!("{e}"))?;
+        Ok(Some((frame, checksum)))
+    }
+}
+
+/// Call `f` with every entry of a storage, in key order.
+fn for_each_entry(
+    store: &impl KvStore,
+    mut f: impl FnMut(Vec<u8>, Vec<u8>) -> Result<(), anyhow::Error>,
+) -> Result<(), anyhow::Error> {
+    let mut next = if store.contains(&[]) {
+        Some(Vec::new())
+    } else {
+        store.next_key(&[]).map_err(|e| anyhow!("{e}"))?
+    };
+    while let Some(key) = next {
+        let value = store
+            .get(&key)
+            .map_err(|e| anyhow!("{e}"))?
+            .unwrap_or_default();
+        next = store.next_key(&key).map_err(|e| anyhow!("{e}"))?;
+        f(key, value)?;
+    }
+    Ok(())
+}
+
+/// Write all root storages of the library to `writer`.
+pub fn export(storage: &StorageLibrary, writer: impl Write) -> Result<(), anyhow::Error> {
+    let mut writer = FrameWriter {
+        inner: writer,
+        hasher: Sha3_256::default(),
+    };
+    writer.write(&Frame::Header { version: VERSION })?;
+
+    for (name, store) in storage.roots() {
+        writer.write(&Frame::Storage {
+            name: name.to_string(),
+        })?;
+
+        let mut count = 0;
+        let mut hasher = EntriesHasher::default();
+        for_each_entry(store, |key, value| {
+            hasher.update(&key, &value);
+            count += 1;
+            writer.write(&Frame::Entry {
+                key: key.into(),
+                value: value.into(),
+            })
+        })?;
+        writer.write(&Frame::StorageEnd {
+            hash: hasher.finish().into(),
+        })?;
+        info!(storage = name, count, "Exported storage");
+    }
+
+    writer.finish()?;
+    Ok(())
+}
+
+/// A storage being restored.
+struct Restoring {
+    name: String,
+    hasher: EntriesHasher,
+    last_key: Option<Vec<u8>>,
+}
+
+/// Restore a snapshot into the root storages of the library, which should be
+/// empty. The content of every storage is checked against the snapshot, and
+/// nothing is committed unless the whole snapshot is valid.
+pub fn import(storage: &mut StorageLibrary, reader: impl Read) -> Result<(), anyhow::Error> {
+    let result = restore(storage, reader);
+    if result.is_err() {
+        storage.rollback().map_err(|e| anyhow!("{e}"))?;
+    }
+    result
+}
+
+fn restore(storage: &mut StorageLibrary, reader: impl Read) -> Result<(), anyhow::Error> {
+    let mut reader = FrameReader {
+        inner: reader,
+        hasher: Sha3_256::default(),
+    };
+
+    match reader.read()? {
+        Some((Frame::Header { version }, _)) if version == VERSION => {}
+        Some((Frame::Header { version }, _)) => bail!("Unsupported snapshot version {version}."),
+        _ => bail!("Invalid snapshot header."),
+    }
+
+    let mut current: Option<Restoring> = None;
+    let mut names = Vec::new();
+
+    loop {
+        let (frame, checksum) = reader
+            .read()?
+            .ok_or_else(|| anyhow!("Unexpected end of snapshot."))?;
+
+        match frame {
+            Frame::Header { .. } => bail!("Unexpected header in snapshot."),
+            Frame::Storage { name } => {
+                if let Some(Restoring { name, .. }) = current {
+                    bail!("Storage '{name}' has no hash in snapshot.");
+                }
+                if !storage.roots().any(|(n, _)| n == name) {
+                    bail!("Unknown root storage '{name}' in snapshot.");
+                }
+                if names.contains(&name) {
+                    bail!("Storage '{name}' appears twice in snapshot.");
+                }
+                current = Some(Restoring {
+                    name,
+                    hasher: EntriesHasher::default(),
+                    last_key: None,
+                });
+            }
+            Frame::Entry { key, value } => {
+                let restoring = current
+                    .as_mut()
+                    .ok_or_else(|| anyhow!("Entry outside of a storage in snapshot."))?;
+                if matches!(&restoring.last_key, Some(last) if last.as_slice() >= key.as_slice()) {
+                    bail!("Entries of storage '{}' are not in order.", restoring.name);
+                }
+                restoring.hasher.update(key.as_slice(), value.as_slice());
+                restoring.last_key = Some(key.to_vec());
+                storage
+                    .get_mut(&restoring.name)
+                    .ok_or_else(|| anyhow!("Unknown storage '{}'.", restoring.name))?
+                    .set(key.to_vec(), value.to_vec())
+                    .map_err(|e| anyhow!("{e}"))?;
+            }
+            Frame::StorageEnd { hash } => {
+                let Restoring { name, hasher, .. } = current
+                    .take()
+                    .ok_or_else(|| anyhow!("Hash outside of a storage in snapshot."))?;
+                check_hash(hasher.finish(), &hash, &name)?;
+                check_content(storage, &name, &hash)?;
+                names.push(name);
+            }
+            Frame::End { checksum: expected } => {
+                if let Some(Restoring { name, .. }) = current {
+                    bail!("Storage '{name}' has no hash in snapshot.");
+                }
+                if expected.as_slice() != checksum.as_slice() {
+                    bail!("Invalid snapshot checksum.");
+                }
+                break;
+            }
+        }
+    }
+
+    for name in names {
+        storage
+            .get_mut(&name)
+            .ok_or_else(|| anyhow!("Unknown storage '{name}'."))?
+            .commit()
+            .map_err(|e| anyhow!("{e}"))?;
+        info!(storage = %name, "Imported storage");
+    }
+    Ok(())
+}
+
+fn check_hash(actual: Vec<u8>, expected: &[u8], name: &str) -> Result<(), anyhow::Error> {
+    if actual != expected {
+        bail!(
+            "Hash mismatch for storage '{name}': expected {}, got {}.",
+            hex::encode(expected),
+            hex::encode(actual)
+        );
+    }
+    Ok(())
+}
+
+/// Check that a storage only contains the restored entries, which would not
+/// be the case if it wasn't empty.
+fn check_content(
+    storage: &StorageLibrary,
+    name: &str,
+    expected: &[u8],
+) -> Result<(), anyhow::Error> {
+    let store = storage
+        .get(name)
+        .ok_or_else(|| anyhow!("Unknown storage '{name}'."))?;
+    let mut hasher = EntriesHasher::default();
+    for_each_entry(store, |key, value| {
+        hasher.update(key, value);
+        Ok(())
+    })?;
+    check_hash(hasher.finish(), expected, name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::StorageConfig;
+
+    fn library(dir: &tempfile::TempDir) -> StorageLibrary {
+        let config: StorageConfig = serde_json::from_value(serde_json::json!([
+            { "type": "Memory", "name": "memory" },
+            { "type": "Merk", "name": "merk", "path": "merk.db" },
+            { "type": "Prefixed", "name": "prefixed", "prefix": "/p/", "backend": "merk" },
+        ]))
+        .unwrap();
+        StorageLibrary::create(config, dir.path(), true).unwrap()
+    }
+
+    fn fill(storage: &mut StorageLibrary) {
+        // One commit per write, so Merk's tree is not shaped like after a
+        // single batch.
+        for name in ["memory", "merk", "prefixed"] {
+            for i in 0..100u32 {
+                storage
+                    .get_mut(name)
+                    .unwrap()
+                    .set(i.to_be_bytes().to_vec(), name.as_bytes().to_vec())
+                    .unwrap();
+                storage.commit().unwrap();
+            }
+        }
+        let store = storage.get_mut("merk").unwrap();
+        store.set(Vec::new(), b"empty key".to_vec()).unwrap();
+        store.del(&7u32.to_be_bytes()).unwrap();
+        storage.commit().unwrap();
+    }
+
+    fn entries(store: &impl KvStore) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let mut entries = Vec::new();
+        for_each_entry(store, |key, value| {
+            entries.push((key, value));
+            Ok(())
+        })
+        .unwrap();
+        entries
+    }
+
+    #[test]
+    fn roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut storage = library(&dir);
+        fill(&mut storage);
+        let mut bytes = Vec::new();
+        export(&storage, &mut bytes).unwrap();
+
+        let restored_dir = tempfile::tempdir().unwrap();
+        let mut restored = library(&restored_dir);
+        import(&mut restored, bytes.as_slice()).unwrap();
+
+        for name in ["memory", "merk", "prefixed"] {
+            let (expected, actual) = (storage.get(name).unwrap(), restored.get(name).unwrap());
+            assert_eq!(entries(expected), entries(actual), "{name}");
+        }
+        assert_eq!(
+            storage.get("memory").unwrap().hash().unwrap(),
+            restored.get("memory").unwrap().hash().unwrap()
+        );
+    }
+
+    #[test]
+    fn not_empty() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut storage = library(&dir);
+        fill(&mut storage);
+        let mut bytes = Vec::new();
+        export(&storage, &mut bytes).unwrap();
+
+        let restored_dir = tempfile::tempdir().unwrap();
+        let mut restored = library(&restored_dir);
+        let memory = restored.get_mut("memory").unwrap();
+        memory.set(b"other".to_vec(), Vec::new()).unwrap();
+        memory.commit().unwrap();
+
+        assert!(import(&mut restored, bytes.as_slice()).is_err());
+        assert!(entries(restored.get("merk").unwrap()).is_empty());
+    }
+
+    #[test]
+    fn corrupted() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut storage = library(&dir);
+        fill(&mut storage);
+        let mut bytes = Vec::new();
+        export(&storage, &mut bytes).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+
+        let restored_dir = tempfile::tempdir().unwrap();
+        let mut restored = library(&restored_dir);
+        assert!(import(&mut restored, bytes.as_slice()).is_err());
+        assert!(entries(restored.get("memory").unwrap()).is_empty());
+    }
+}
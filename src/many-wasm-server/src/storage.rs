@@ -11,8 +11,38 @@ pub trait KvStore: Send {
     fn del(&mut self, key: &[u8]) -> Result<(), ManyError>;
     fn contains(&self, key: &[u8]) -> bool;
     fn size(&self, key: &[u8]) -> Option<usize>;
-    fn hash(&self) -> Vec<u8>;
+
+    /// Hash of the committed state. Writes since the last commit are not
+    /// part of it.
+    fn hash(&self) -> Result<Vec<u8>, ManyError>;
     fn commit(&mut self) -> Result<(), ManyError>;
+
+    /// Discard all writes since the last commit.
+    fn rollback(&mut self) -> Result<(), ManyError>;
+
+    /// Remember the writes made so far, so the ones made afterward can be
+    /// discarded alone. Savepoints nest, and are dropped on `commit` and
+    /// `rollback`. Views only save their own state, not the storage below.
+    fn savepoint(&mut self) -> Result<(), ManyError>;
+
+    /// Forget the last savepoint, keeping the writes made since.
+    fn release_savepoint(&mut self) -> Result<(), ManyError>;
+
+    /// Discard the writes made since the last savepoint, and forget it.
+    fn rollback_to_savepoint(&mut self) -> Result<(), ManyError>;
+
+    /// The first key strictly greater than `after`, in byte order.
+    fn next_key(&self, after: &[u8]) -> Result<Option<Vec<u8>>, ManyError>;
+
+    /// The last committed height, for storages that keep their history.
+    fn height(&self) -> Option<u64> {
+        None
+    }
+
+    /// Read the state as of a past height. All writes fail afterward.
+    fn checkout(&mut self, _height: u64) -> Result<(), ManyError> {
+        Err(ManyError::unknown("Storage does not keep its history."))
+    }
 }
 
 pub struct NullKvStore;
@@ -38,22 +68,52 @@ impl KvStore for NullKvStore {
         None
     }
 
-    fn hash(&self) -> Vec<u8> {
-        Vec::new()
+    fn hash(&self) -> Result<Vec<u8>, ManyError> {
+        Ok(Vec::new())
     }
 
     fn commit(&mut self) -> Result<(), ManyError> {
         Ok(())
     }
+
+    fn rollback(&mut self) -> Result<(), ManyError> {
+        Ok(())
+    }
+
+    fn savepoint(&mut self) -> Result<(), ManyError> {
+        Ok(())
+    }
+
+    fn release_savepoint(&mut self) -> Result<(), ManyError> {
+        Ok(())
+    }
+
+    fn rollback_to_savepoint(&mut self) -> Result<(), ManyError> {
+        Ok(())
+    }
+
+    fn next_key(&self, _after: &[u8]) -> Result<Option<Vec<u8>>, ManyError> {
+        Ok(None)
+    }
 }
 
 pub mod memory;
 pub mod merk;
+pub mod overlay;
+pub mod pending;
+pub mod readonly;
+pub mod rocksdb;
+pub mod sqlite;
+pub mod versioned;
 
 #[derive(Clone)]
 pub struct StorageRef {
     inner: Arc<Mutex<dyn KvStore>>,
     prefix: Option<Vec<u8>>,
+
+    /// Whether this is a view over another storage of the library, in which
+    /// case its content is already part of that storage.
+    view: bool,
 }
 
 impl StorageRef {
@@ -61,6 +121,15 @@ impl StorageRef {
         Self {
             inner: Arc::new(Mutex::new(store)),
             prefix: None,
+            view: false,
+        }
+    }
+
+    pub fn new_view(store: impl KvStore + 'static) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(store)),
+            prefix: None,
+            view: true,
         }
     }
 
@@ -68,6 +137,7 @@ impl StorageRef {
         Self {
             inner: Arc::new(Mutex::new(store)),
             prefix: Some(prefix),
+            view: false,
         }
     }
 
@@ -75,6 +145,7 @@ impl StorageRef {
         Self {
             inner: self.inner.clone(),
             prefix: None,
+            view: self.view,
         }
     }
 
@@ -84,6 +155,7 @@ impl StorageRef {
         Self {
             inner: self.inner.clone(),
             prefix: Some(new_prefix),
+            view: self.view,
         }
     }
 
@@ -128,24 +200,71 @@ impl KvStore for StorageRef {
     }
 
     fn contains(&self, key: &[u8]) -> bool {
-        self.inner.lock().unwrap().contains(key)
+        let key = self._key(key.into());
+        self.inner.lock().unwrap().contains(key.as_ref())
     }
 
     fn size(&self, key: &[u8]) -> Option<usize> {
-        self.inner.lock().ok()?.size(key)
+        let key = self._key(key.into());
+        self.inner.lock().ok()?.size(key.as_ref())
     }
 
-    fn hash(&self) -> Vec<u8> {
-        self.inner
-            .lock()
-            .map_err(ManyError::unknown)
-            .unwrap()
-            .hash()
+    fn hash(&self) -> Result<Vec<u8>, ManyError> {
+        self.inner.lock().map_err(ManyError::unknown)?.hash()
     }
 
     fn commit(&mut self) -> Result<(), ManyError> {
         self.inner.lock().map_err(ManyError::unknown)?.commit()
     }
+
+    fn rollback(&mut self) -> Result<(), ManyError> {
+        self.inner.lock().map_err(ManyError::unknown)?.rollback()
+    }
+
+    fn savepoint(&mut self) -> Result<(), ManyError> {
+        self.inner.lock().map_err(ManyError::unknown)?.savepoint()
+    }
+
+    fn release_savepoint(&mut self) -> Result<(), ManyError> {
+        self.inner
+            .lock()
+            .map_err(ManyError::unknown)?
+            .release_savepoint()
+    }
+
+    fn rollback_to_savepoint(&mut self) -> Result<(), ManyError> {
+        self.inner
+            .lock()
+            .map_err(ManyError::unknown)?
+            .rollback_to_savepoint()
+    }
+
+    fn next_key(&self, after: &[u8]) -> Result<Option<Vec<u8>>, ManyError> {
+        let key = self._key(after.into());
+        let next = self
+            .inner
+            .lock()
+            .map_err(ManyError::unknown)?
+            .next_key(key.as_ref())?;
+
+        // Only return keys within the prefix, without it.
+        Ok(match (&self.prefix, next) {
+            (None, next) => next,
+            (Some(p), Some(next)) if next.starts_with(p) => Some(next[p.len()..].to_vec()),
+            (Some(_), _) => None,
+        })
+    }
+
+    fn height(&self) -> Option<u64> {
+        self.inner.lock().ok()?.height()
+    }
+
+    fn checkout(&mut self, height: u64) -> Result<(), ManyError> {
+        self.inner
+            .lock()
+            .map_err(ManyError::unknown)?
+            .checkout(height)
+    }
 }
 
 #[derive(Default)]
@@ -165,12 +284,13 @@ impl StorageLibrary {
     ) -> Result<Self, ManyError> {
         let mut storage = Self::new();
 
-        for config in config {
-            let (name, storage_ref) =
-                config.create_ref(&mut storage, root.as_ref(), create_if_missing)?;
-            if storage.inner.contains_key(&name) {
-                return Err(ManyError::unknown("Storage name already exists."));
-            }
+        for config in config.resolve()? {
+            let name = config.name().to_string();
+            let (name, storage_ref) = config
+                .create_ref(&mut storage, root.as_ref(), create_if_missing)
+                .map_err(|e| {
+                    ManyError::unknown(format!("Could not create storage '{name}': {e}"))
+                })?;
             storage.inner.insert(name, storage_ref);
         }
 
@@ -184,4 +304,90 @@ impl StorageLibrary {
     pub fn get_mut(&mut self, name: impl AsRef<str>) -> Option<&mut StorageRef> {
         self.inner.get_mut(name.as_ref())
     }
+
+    /// Storages that are not a view of another storage.
+    pub fn roots(&self) -> impl Iterator<Item = (&str, &StorageRef)> {
+        self.inner
+            .iter()
+            .filter(|(_, s)| s.prefix.is_none() && !s.view)
+            .map(|(name, s)| (name.as_str(), s))
+    }
+
+    /// Storages without prefix, views first so they are committed (or rolled
+    /// back) before the storages they are built on.
+    fn unprefixed_mut(&mut self) -> impl Iterator<Item = &mut StorageRef> {
+        let (views, roots): (Vec<_>, Vec<_>) = self
+            .inner
+            .values_mut()
+            .filter(|s| s.prefix.is_none())
+            .partition(|s| s.view);
+        views.into_iter().chain(roots)
+    }
+
+    /// Commit all storages. Views are committed before the storages they
+    /// are built on, so their writes are part of the same commit.
+    pub fn commit(&mut self) -> Result<(), ManyError> {
+        for storage in self.unprefixed_mut() {
+            storage.commit()?;
+        }
+        Ok(())
+    }
+
+    /// Discard the writes of all storages since the last commit. All storages
+    /// are rolled back even if one fails, and the first error is returned.
+    pub fn rollback(&mut self) -> Result<(), ManyError> {
+        let mut result = Ok(());
+        for storage in self.unprefixed_mut() {
+            if let Err(e) = storage.rollback() {
+                result = result.and(Err(e));
+            }
+        }
+        result
+    }
+
+    /// Take a savepoint of all storages, see [`KvStore::savepoint`].
+    pub fn savepoint(&mut self) -> Result<(), ManyError> {
+        for storage in self.unprefixed_mut() {
+            storage.savepoint()?;
+        }
+        Ok(())
+    }
+
+    /// Forget the last savepoint of all storages, keeping their writes.
+    pub fn release_savepoint(&mut self) -> Result<(), ManyError> {
+        for storage in self.unprefixed_mut() {
+            storage.release_savepoint()?;
+        }
+        Ok(())
+    }
+
+    /// Discard the writes of all storages since their last savepoint. All
+    /// storages are restored even if one fails, and the first error is
+    /// returned.
+    pub fn rollback_to_savepoint(&mut self) -> Result<(), ManyError> {
+        let mut result = Ok(());
+        for storage in self.unprefixed_mut() {
+            if let Err(e) = storage.rollback_to_savepoint() {
+                result = result.and(Err(e));
+            }
+        }
+        result
+    }
+
+    /// Read all versioned storages as of a past height. Other storages are
+    /// left as is.
+    pub fn checkout(&mut self, height: u64) -> Result<(), ManyError> {
+        let mut found = false;
+        for storage in self.inner.values_mut().filter(|s| s.prefix.is_none()) {
+            if storage.height().is_some() {
+                storage.checkout(height)?;
+                found = true;
+            }
+        }
+        if found {
+            Ok(())
+        } else {
+            Err(ManyError::unknown("No storage keeps its history."))
+        }
+    }
 }
@@ -1,72 +1,147 @@
+use crate::storage::pending::PendingWrites;
 use crate::storage::KvStore;
 use many_error::ManyError;
 use sha3::{Digest, Sha3_256};
 use std::cell::RefCell;
 use std::collections::BTreeMap;
+use std::ops::Bound;
 
+/// Computes the same hash as `hash_entries`, one entry at a time.
+pub(crate) struct EntriesHasher(Sha3_256);
+
+impl Default for EntriesHasher {
+    fn default() -> Self {
+        let mut hasher = Sha3_256::default();
+        hasher.update(b"\0");
+        Self(hasher)
+    }
+}
+
+impl EntriesHasher {
+    /// Entries must be added in key order.
+    pub fn update(&mut self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) {
+        self.0.update(b"key\x01");
+        self.0.update(key);
+        self.0.update(b"key\x02");
+        self.0.update(value);
+    }
+
+    pub fn finish(mut self) -> Vec<u8> {
+        self.0.update(b"\x03");
+        self.0.finalize().to_vec()
+    }
+}
+
+/// Hash of all key-value pairs, which must be iterated in key order.
+pub(crate) fn hash_entries<K, V>(entries: impl IntoIterator<Item = (K, V)>) -> Vec<u8>
+where
+    K: AsRef<[u8]>,
+    V: AsRef<[u8]>,
+{
+    let mut hasher = EntriesHasher::default();
+    for (k, v) in entries {
+        hasher.update(k, v);
+    }
+    hasher.finish()
+}
+
+/// A storage kept in memory. Writes are pending until `commit()`, so they
+/// can be rolled back.
 #[derive(Default)]
 pub struct MemoryStorage {
     // This is a refcell as it needs to be replaced in hash() which doesn't have mutable rights.
     hash: RefCell<Option<Vec<u8>>>,
     inner: BTreeMap<Vec<u8>, Vec<u8>>,
+    pending: PendingWrites,
 }
 
 impl MemoryStorage {
+    /// Insert a committed value, e.g. from the configuration.
     pub fn insert(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.hash.replace(None);
         self.inner.insert(key, value);
     }
 }
 
 impl KvStore for MemoryStorage {
     fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, ManyError> {
-        Ok(self
-            .inner
-            .get(key)
-            .map(|x| x.as_slice())
-            .map(|v| v.to_vec()))
+        Ok(match self.pending.get(key) {
+            Some(value) => value.cloned(),
+            None => self.inner.get(key).cloned(),
+        })
     }
 
     fn set(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<(), ManyError> {
-        self.hash.replace(None);
-        self.inner.insert(key, value);
+        self.pending.set(key, value);
         Ok(())
     }
 
     fn del(&mut self, key: &[u8]) -> Result<(), ManyError> {
-        self.hash.replace(None);
-        self.inner.remove(key);
+        self.pending.del(key);
         Ok(())
     }
 
     fn contains(&self, key: &[u8]) -> bool {
-        self.inner.contains_key(key)
+        match self.pending.get(key) {
+            Some(value) => value.is_some(),
+            None => self.inner.contains_key(key),
+        }
     }
 
     fn size(&self, key: &[u8]) -> Option<usize> {
-        Some(self.inner.get(key)?.len())
+        Some(self.get(key).ok()??.len())
     }
 
-    fn hash(&self) -> Vec<u8> {
+    fn hash(&self) -> Result<Vec<u8>, ManyError> {
         if let Some(ref hash) = *self.hash.borrow() {
-            return hash.to_vec();
-        } else {
-            let mut hasher = Sha3_256::default();
-
-            hasher.update(b"\0");
-            for (k, v) in &self.inner {
-                hasher.update(b"key\x01");
-                hasher.update(k);
-                hasher.update(b"key\x02");
-                hasher.update(v);
-            }
-            hasher.update(b"\x03");
-            self.hash.replace(Some(hasher.finalize().to_vec()));
+            return Ok(hash.to_vec());
         }
-        self.hash()
+        let hash = hash_entries(&self.inner);
+        self.hash.replace(Some(hash.clone()));
+        Ok(hash)
     }
 
-    // Never anything to commit.
     fn commit(&mut self) -> Result<(), ManyError> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        self.hash.replace(None);
+        for (key, value) in self.pending.take() {
+            match value {
+                Some(value) => self.inner.insert(key, value),
+                None => self.inner.remove(&key),
+            };
+        }
+        Ok(())
+    }
+
+    fn rollback(&mut self) -> Result<(), ManyError> {
+        self.pending.clear();
+        Ok(())
+    }
+
+    fn savepoint(&mut self) -> Result<(), ManyError> {
+        self.pending.savepoint();
         Ok(())
     }
+
+    fn release_savepoint(&mut self) -> Result<(), ManyError> {
+        self.pending.release_savepoint();
+        Ok(())
+    }
+
+    fn rollback_to_savepoint(&mut self) -> Result<(), ManyError> {
+        self.pending.rollback_to_savepoint();
+        Ok(())
+    }
+
+    fn next_key(&self, after: &[u8]) -> Result<Option<Vec<u8>>, ManyError> {
+        self.pending.next_key(after, |after| {
+            Ok(self
+                .inner
+                .range::<[u8], _>((Bound::Excluded(after), Bound::Unbounded))
+                .next()
+                .map(|(k, _)| k.clone()))
+        })
+    }
 }
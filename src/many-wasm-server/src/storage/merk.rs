@@ -1,10 +1,14 @@
+use crate::storage::pending::PendingWrites;
 use crate::storage::KvStore;
 use many_error::ManyError;
 use merk::Op;
 use std::path::Path;
 
+/// A storage on a Merk tree. Writes are pending until `commit()`, where they
+/// are applied to the tree as a single batch.
 pub struct MerkStorage {
     merk: merk::Merk,
+    pending: PendingWrites,
 }
 
 impl MerkStorage {
@@ -13,7 +17,10 @@ impl MerkStorage {
         opts.create_if_missing(false);
 
         let merk = merk::Merk::open_opt(path, opts).map_err(ManyError::unknown)?;
-        Ok(Self { merk })
+        Ok(Self {
+            merk,
+            pending: PendingWrites::default(),
+        })
     }
     pub fn new(path: impl AsRef<Path>, delete_if_exists: bool) -> Result<Self, ManyError> {
         if delete_if_exists && path.as_ref().exists() {
@@ -21,42 +28,90 @@ impl MerkStorage {
         }
 
         let merk = merk::Merk::open(path).map_err(ManyError::unknown)?;
-        Ok(Self { merk })
+        Ok(Self {
+            merk,
+            pending: PendingWrites::default(),
+        })
     }
 }
 
 impl KvStore for MerkStorage {
     fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, ManyError> {
-        self.merk.get(key).map_err(|e| ManyError::unknown(e))
+        match self.pending.get(key) {
+            Some(value) => Ok(value.cloned()),
+            None => self.merk.get(key).map_err(|e| ManyError::unknown(e)),
+        }
     }
 
     fn set(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<(), ManyError> {
-        self.merk
-            .apply(&[(key, Op::Put(value))])
-            .map_err(|e| ManyError::unknown(e))?;
+        self.pending.set(key, value);
         Ok(())
     }
 
     fn del(&mut self, key: &[u8]) -> Result<(), ManyError> {
-        self.merk
-            .apply(&[(key.to_vec(), Op::Delete)])
-            .map_err(|e| ManyError::unknown(e))?;
+        self.pending.del(key);
         Ok(())
     }
 
     fn contains(&self, key: &[u8]) -> bool {
-        self.merk.get(key).map_or(false, |v| v.is_some())
+        self.get(key).map_or(false, |v| v.is_some())
     }
 
     fn size(&self, key: &[u8]) -> Option<usize> {
-        Some(self.merk.get(key).ok()??.len())
+        Some(self.get(key).ok()??.len())
     }
 
-    fn hash(&self) -> Vec<u8> {
-        self.merk.root_hash().to_vec()
+    fn hash(&self) -> Result<Vec<u8>, ManyError> {
+        Ok(self.merk.root_hash().to_vec())
     }
 
     fn commit(&mut self) -> Result<(), ManyError> {
+        let mut batch = Vec::new();
+        for (key, value) in self.pending.take() {
+            match value {
+                Some(value) => batch.push((key, Op::Put(value))),
+                // Merk refuses to delete keys that are not in the tree.
+                None => {
+                    if self.merk.get(&key).map_err(ManyError::unknown)?.is_some() {
+                        batch.push((key, Op::Delete));
+                    }
+                }
+            }
+        }
+
+        // The batch is sorted, as pending writes are.
+        self.merk.apply(&batch).map_err(|e| ManyError::unknown(e))?;
         self.merk.commit(&[]).map_err(|e| ManyError::unknown(e))
     }
+
+    fn rollback(&mut self) -> Result<(), ManyError> {
+        self.pending.clear();
+        Ok(())
+    }
+
+    fn savepoint(&mut self) -> Result<(), ManyError> {
+        self.pending.savepoint();
+        Ok(())
+    }
+
+    fn release_savepoint(&mut self) -> Result<(), ManyError> {
+        self.pending.release_savepoint();
+        Ok(())
+    }
+
+    fn rollback_to_savepoint(&mut self) -> Result<(), ManyError> {
+        self.pending.rollback_to_savepoint();
+        Ok(())
+    }
+
+    fn next_key(&self, after: &[u8]) -> Result<Option<Vec<u8>>, ManyError> {
+        self.pending.next_key(after, |after| {
+            let mut iter = self.merk.raw_iter();
+            iter.seek(after);
+            if iter.key() == Some(after) {
+                iter.next();
+            }
+            Ok(iter.key().map(|k| k.to_vec()))
+        })
+    }
 }
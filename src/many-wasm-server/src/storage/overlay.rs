@@ -0,0 +1,270 @@
+use crate::storage::memory::hash_entries;
+use crate::storage::pending::PendingWrites;
+use crate::storage::KvStore;
+use many_error::ManyError;
+use std::cell::RefCell;
+
+/// A storage that keeps all writes in memory on top of another storage, which
+/// is never written to. Committed writes are kept across commits, and lost on
+/// restart.
+///
+/// Until something is committed, the hash is the one of the base. After that,
+/// it is computed like `MemoryStorage`, over the entries of the base with the
+/// committed writes on top, so it differs from the native hash of the base
+/// (e.g. a Merk root hash) even if the same values were written back. It is
+/// kept until the next commit, so changes to the base in between are not
+/// reflected.
+pub struct OverlayStorage<S: KvStore> {
+    base: S,
+    writes: PendingWrites,
+    pending: PendingWrites,
+
+    // See `MemoryStorage` for why this is a refcell.
+    hash: RefCell<Option<Vec<u8>>>,
+}
+
+impl<S: KvStore> OverlayStorage<S> {
+    pub fn new(base: S) -> Self {
+        Self {
+            base,
+            writes: PendingWrites::default(),
+            pending: PendingWrites::default(),
+            hash: RefCell::new(None),
+        }
+    }
+
+    /// The value of a key, ignoring writes that are not committed.
+    fn get_committed(&self, key: &[u8]) -> Result<Option<Vec<u8>>, ManyError> {
+        match self.writes.get(key) {
+            Some(value) => Ok(value.cloned()),
+            None => self.base.get(key),
+        }
+    }
+
+    fn next_committed_key(&self, after: &[u8]) -> Result<Option<Vec<u8>>, ManyError> {
+        self.writes
+            .next_key(after, |after| self.base.next_key(after))
+    }
+
+    /// The hash of all committed entries.
+    fn entries_hash(&self) -> Result<Vec<u8>, ManyError> {
+        let mut entries = Vec::new();
+        if let Some(value) = self.get_committed(&[])? {
+            entries.push((Vec::new(), value));
+        }
+        let mut next = self.next_committed_key(&[])?;
+        while let Some(key) = next {
+            let value = self.get_committed(&key)?.unwrap_or_default();
+            next = self.next_committed_key(&key)?;
+            entries.push((key, value));
+        }
+        Ok(hash_entries(entries))
+    }
+}
+
+impl<S: KvStore> KvStore for OverlayStorage<S> {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, ManyError> {
+        match self.pending.get(key).or_else(|| self.writes.get(key)) {
+            Some(value) => Ok(value.cloned()),
+            None => self.base.get(key),
+        }
+    }
+
+    fn set(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<(), ManyError> {
+        self.pending.set(key, value);
+        Ok(())
+    }
+
+    fn del(&mut self, key: &[u8]) -> Result<(), ManyError> {
+        self.pending.del(key);
+        Ok(())
+    }
+
+    fn contains(&self, key: &[u8]) -> bool {
+        match self.pending.get(key).or_else(|| self.writes.get(key)) {
+            Some(value) => value.is_some(),
+            None => self.base.contains(key),
+        }
+    }
+
+    fn size(&self, key: &[u8]) -> Option<usize> {
+        match self.pending.get(key).or_else(|| self.writes.get(key)) {
+            Some(value) => value.map(Vec::len),
+            None => self.base.size(key),
+        }
+    }
+
+    fn hash(&self) -> Result<Vec<u8>, ManyError> {
+        if self.writes.is_empty() {
+            return self.base.hash();
+        }
+        if let Some(ref hash) = *self.hash.borrow() {
+            return Ok(hash.to_vec());
+        }
+        let hash = self.entries_hash()?;
+        self.hash.replace(Some(hash.clone()));
+        Ok(hash)
+    }
+
+    // Never persisted.
+    fn commit(&mut self) -> Result<(), ManyError> {
+        // The base may have been committed too.
+        self.hash.replace(None);
+        self.writes.extend(self.pending.take());
+        Ok(())
+    }
+
+    fn rollback(&mut self) -> Result<(), ManyError> {
+        self.pending.clear();
+        Ok(())
+    }
+
+    fn savepoint(&mut self) -> Result<(), ManyError> {
+        self.pending.savepoint();
+        Ok(())
+    }
+
+    fn release_savepoint(&mut self) -> Result<(), ManyError> {
+        self.pending.release_savepoint();
+        Ok(())
+    }
+
+    fn rollback_to_savepoint(&mut self) -> Result<(), ManyError> {
+        self.pending.rollback_to_savepoint();
+        Ok(())
+    }
+
+    fn next_key(&self, after: &[u8]) -> Result<Option<Vec<u8>>, ManyError> {
+        self.pending
+            .next_key(after, |after| self.next_committed_key(after))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::memory::MemoryStorage;
+    use crate::storage::readonly::ReadOnlyStorage;
+    use crate::storage::NullKvStore;
+
+    fn base() -> MemoryStorage {
+        let mut base = MemoryStorage::default();
+        base.insert(b"a".to_vec(), b"1".to_vec());
+        base.insert(b"b".to_vec(), b"2".to_vec());
+        base
+    }
+
+    fn keys(store: &impl KvStore) -> Vec<Vec<u8>> {
+        let mut keys = Vec::new();
+        let mut after = Vec::new();
+        while let Some(key) = store.next_key(&after).unwrap() {
+            keys.push(key.clone());
+            after = key;
+        }
+        keys
+    }
+
+    #[test]
+    fn shadowing() {
+        let mut s = OverlayStorage::new(base());
+        assert_eq!(s.hash().unwrap(), base().hash().unwrap());
+
+        s.set(b"a".to_vec(), b"3".to_vec()).unwrap();
+        s.del(b"b").unwrap();
+        s.set(b"c".to_vec(), b"4".to_vec()).unwrap();
+        assert_eq!(s.get(b"a").unwrap(), Some(b"3".to_vec()));
+        assert!(!s.contains(b"b"));
+        assert_eq!(keys(&s), vec![b"a".to_vec(), b"c".to_vec()]);
+        assert_eq!(s.hash().unwrap(), base().hash().unwrap());
+
+        s.commit().unwrap();
+        assert_eq!(
+            s.hash().unwrap(),
+            hash_entries([(b"a", b"3"), (b"c", b"4")])
+        );
+        assert_eq!(s.base.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(s.base.get(b"c").unwrap(), None);
+
+        // Writing back the base values gives back the base hash.
+        s.set(b"a".to_vec(), b"1".to_vec()).unwrap();
+        s.set(b"b".to_vec(), b"2".to_vec()).unwrap();
+        s.del(b"c").unwrap();
+        s.commit().unwrap();
+        assert_eq!(s.hash().unwrap(), base().hash().unwrap());
+    }
+
+    #[test]
+    fn base_hash() {
+        // Without committed writes, the hash is the one of the base, even if
+        // it isn't computed over the entries.
+        let mut s = OverlayStorage::new(NullKvStore);
+        assert_eq!(s.hash().unwrap(), NullKvStore.hash().unwrap());
+        s.set(b"a".to_vec(), b"1".to_vec()).unwrap();
+        s.commit().unwrap();
+        assert_eq!(s.hash().unwrap(), hash_entries([(b"a", b"1")]));
+    }
+
+    #[test]
+    fn rollback() {
+        let mut s = OverlayStorage::new(base());
+        s.del(b"a").unwrap();
+        s.commit().unwrap();
+        s.set(b"a".to_vec(), b"3".to_vec()).unwrap();
+        s.del(b"b").unwrap();
+        s.rollback().unwrap();
+
+        assert_eq!(s.get(b"a").unwrap(), None);
+        assert_eq!(s.get(b"b").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(keys(&s), vec![b"b".to_vec()]);
+    }
+
+    #[test]
+    fn base_changes() {
+        let mut s = OverlayStorage::new(base());
+        s.set(b"c".to_vec(), b"4".to_vec()).unwrap();
+        s.commit().unwrap();
+        let before = s.hash().unwrap();
+
+        // The hash is only updated on commit.
+        s.base.insert(b"d".to_vec(), b"5".to_vec());
+        assert_eq!(s.hash().unwrap(), before);
+        s.commit().unwrap();
+        assert_ne!(s.hash().unwrap(), before);
+        assert_eq!(
+            keys(&s),
+            vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec(), b"d".to_vec()]
+        );
+    }
+
+    #[test]
+    fn read_only() {
+        let mut s = ReadOnlyStorage::new(base());
+        assert!(s.set(b"c".to_vec(), b"4".to_vec()).is_err());
+        assert!(s.del(b"a").is_err());
+        s.commit().unwrap();
+        assert_eq!(s.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(s.get(b"c").unwrap(), None);
+        assert_eq!(s.hash().unwrap(), base().hash().unwrap());
+    }
+
+    #[test]
+    fn savepoints() {
+        let mut s = OverlayStorage::new(base());
+        s.set(b"c".to_vec(), b"3".to_vec()).unwrap();
+        s.savepoint().unwrap();
+        s.del(b"a").unwrap();
+        s.savepoint().unwrap();
+        s.set(b"d".to_vec(), b"4".to_vec()).unwrap();
+        s.rollback_to_savepoint().unwrap();
+        assert_eq!(s.get(b"d").unwrap(), None);
+        assert_eq!(s.get(b"a").unwrap(), None);
+
+        s.rollback_to_savepoint().unwrap();
+        assert_eq!(s.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(s.get(b"c").unwrap(), Some(b"3".to_vec()));
+
+        // Nothing left to restore.
+        s.rollback_to_savepoint().unwrap();
+        assert_eq!(s.get(b"c").unwrap(), Some(b"3".to_vec()));
+    }
+}
@@ -0,0 +1,97 @@
+use many_error::ManyError;
+use std::collections::BTreeMap;
+use std::ops::Bound;
+
+type Writes = BTreeMap<Vec<u8>, Option<Vec<u8>>>;
+
+/// Writes that are not committed yet, on top of another storage. Deleted keys
+/// are kept as `None` so they hide the keys of that storage.
+#[derive(Default)]
+pub struct PendingWrites {
+    writes: Writes,
+
+    /// The writes as of each savepoint, the last one being the most recent.
+    savepoints: Vec<Writes>,
+}
+
+impl PendingWrites {
+    /// The pending value of a key. `None` if it was not written, `Some(None)`
+    /// if it was deleted.
+    pub fn get(&self, key: &[u8]) -> Option<Option<&Vec<u8>>> {
+        self.writes.get(key).map(Option::as_ref)
+    }
+
+    pub fn set(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.writes.insert(key, Some(value));
+    }
+
+    pub fn del(&mut self, key: &[u8]) {
+        self.writes.insert(key.to_vec(), None);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.writes.is_empty()
+    }
+
+    /// Remove all pending writes and savepoints.
+    pub fn clear(&mut self) {
+        self.writes.clear();
+        self.savepoints.clear();
+    }
+
+    /// Remove all pending writes, in key order, and all savepoints.
+    pub fn take(&mut self) -> Writes {
+        self.savepoints.clear();
+        std::mem::take(&mut self.writes)
+    }
+
+    /// Add writes on top of these ones.
+    pub fn extend(&mut self, other: Writes) {
+        self.writes.extend(other);
+    }
+
+    /// Remember the current writes, to restore them later.
+    pub fn savepoint(&mut self) {
+        self.savepoints.push(self.writes.clone());
+    }
+
+    /// Forget the last savepoint, keeping the writes made since.
+    pub fn release_savepoint(&mut self) {
+        self.savepoints.pop();
+    }
+
+    /// Restore the writes as of the last savepoint, and forget it.
+    pub fn rollback_to_savepoint(&mut self) {
+        if let Some(writes) = self.savepoints.pop() {
+            self.writes = writes;
+        }
+    }
+
+    /// The first key strictly greater than `after`, of the pending writes
+    /// merged with the storage below, whose `next_key` is `base`.
+    pub fn next_key(
+        &self,
+        after: &[u8],
+        base: impl Fn(&[u8]) -> Result<Option<Vec<u8>>, ManyError>,
+    ) -> Result<Option<Vec<u8>>, ManyError> {
+        // Next key below that wasn't deleted.
+        let mut from_base = base(after)?;
+        while let Some(key) = &from_base {
+            if !matches!(self.writes.get(key), Some(None)) {
+                break;
+            }
+            from_base = base(key)?;
+        }
+
+        let from_pending = self
+            .writes
+            .range::<[u8], _>((Bound::Excluded(after), Bound::Unbounded))
+            .find(|(_, v)| v.is_some())
+            .map(|(k, _)| k.clone());
+
+        Ok(match (from_base, from_pending) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        })
+    }
+}
@@ -0,0 +1,64 @@
+use crate::storage::KvStore;
+use many_error::ManyError;
+
+/// A storage that can only be read. Writes fail, and committing does nothing
+/// as the underlying storage is committed on its own.
+pub struct ReadOnlyStorage<S: KvStore> {
+    inner: S,
+}
+
+impl<S: KvStore> ReadOnlyStorage<S> {
+    pub fn new(inner: S) -> Self {
+        Self { inner }
+    }
+}
+
+impl<S: KvStore> KvStore for ReadOnlyStorage<S> {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, ManyError> {
+        self.inner.get(key)
+    }
+
+    fn set(&mut self, _key: Vec<u8>, _value: Vec<u8>) -> Result<(), ManyError> {
+        Err(ManyError::unknown("Storage is read-only."))
+    }
+
+    fn del(&mut self, _key: &[u8]) -> Result<(), ManyError> {
+        Err(ManyError::unknown("Storage is read-only."))
+    }
+
+    fn contains(&self, key: &[u8]) -> bool {
+        self.inner.contains(key)
+    }
+
+    fn size(&self, key: &[u8]) -> Option<usize> {
+        self.inner.size(key)
+    }
+
+    fn hash(&self) -> Result<Vec<u8>, ManyError> {
+        self.inner.hash()
+    }
+
+    fn commit(&mut self) -> Result<(), ManyError> {
+        Ok(())
+    }
+
+    fn rollback(&mut self) -> Result<(), ManyError> {
+        Ok(())
+    }
+
+    fn savepoint(&mut self) -> Result<(), ManyError> {
+        Ok(())
+    }
+
+    fn release_savepoint(&mut self) -> Result<(), ManyError> {
+        Ok(())
+    }
+
+    fn rollback_to_savepoint(&mut self) -> Result<(), ManyError> {
+        Ok(())
+    }
+
+    fn next_key(&self, after: &[u8]) -> Result<Option<Vec<u8>>, ManyError> {
+        self.inner.next_key(after)
+    }
+}
@@ -0,0 +1,313 @@
+use crate::storage::pending::PendingWrites;
+use crate::storage::KvStore;
+use many_error::ManyError;
+use rocksdb::{ColumnFamily, ColumnFamilyDescriptor, IteratorMode, Options, WriteBatch, DB};
+use schemars::JsonSchema;
+use serde_derive::{Deserialize, Serialize};
+use sha3::{Digest, Sha3_256};
+use std::path::Path;
+
+const META_CF: &str = "meta";
+
+/// The digest of the committed entries. It is written with every commit when
+/// the strategy is `Xor`, and removed otherwise, so it is never stale.
+const DIGEST_KEY: &[u8] = b"xor_digest";
+
+type EntriesDigest = [u8; 32];
+
+/// How the hash of a `RocksDbStorage` is computed.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum HashStrategy {
+    /// No hash is computed, `hash()` always returns an empty vector.
+    #[default]
+    None,
+
+    /// The XOR of the hashes of all entries, which doesn't depend on their
+    /// order. It is updated on every commit from the previous values of the
+    /// keys written, and kept in the database. It is only computed from all
+    /// entries when opening a database that doesn't have it yet.
+    Xor,
+}
+
+/// The hash of a single entry, as part of the `Xor` digest.
+fn entry_hash(key: &[u8], value: &[u8]) -> EntriesDigest {
+    let mut hasher = Sha3_256::default();
+    hasher.update((key.len() as u64).to_be_bytes());
+    hasher.update(key);
+    hasher.update(value);
+    hasher.finalize().into()
+}
+
+fn xor(digest: &mut EntriesDigest, hash: EntriesDigest) {
+    for (d, h) in digest.iter_mut().zip(hash) {
+        *d ^= h;
+    }
+}
+
+/// A storage directly on RocksDB, without Merkle tree. Writes are kept in
+/// memory and written in a single batch on `commit()`.
+pub struct RocksDbStorage {
+    db: DB,
+    pending: PendingWrites,
+    strategy: HashStrategy,
+
+    /// The digest of the committed entries, if the strategy is `Xor`.
+    digest: EntriesDigest,
+}
+
+impl RocksDbStorage {
+    fn open(
+        path: &Path,
+        create_if_missing: bool,
+        strategy: HashStrategy,
+    ) -> Result<Self, ManyError> {
+        let mut opts = Options::default();
+        opts.create_if_missing(create_if_missing);
+        opts.create_missing_column_families(true);
+
+        let db = DB::open_cf_descriptors(
+            &opts,
+            path,
+            vec![ColumnFamilyDescriptor::new(META_CF, Options::default())],
+        )
+        .map_err(ManyError::unknown)?;
+
+        let mut storage = Self {
+            db,
+            pending: PendingWrites::default(),
+            strategy,
+            digest: EntriesDigest::default(),
+        };
+        if strategy == HashStrategy::Xor {
+            storage.digest = storage.load_digest()?;
+        }
+        Ok(storage)
+    }
+
+    pub fn load(path: impl AsRef<Path>, strategy: HashStrategy) -> Result<Self, ManyError> {
+        Self::open(path.as_ref(), false, strategy)
+    }
+
+    pub fn new(
+        path: impl AsRef<Path>,
+        delete_if_exists: bool,
+        strategy: HashStrategy,
+    ) -> Result<Self, ManyError> {
+        if delete_if_exists && path.as_ref().exists() {
+            std::fs::remove_dir_all(path.as_ref()).map_err(ManyError::unknown)?;
+        }
+
+        Self::open(path.as_ref(), true, strategy)
+    }
+
+    fn meta(&self) -> Result<&ColumnFamily, ManyError> {
+        self.db
+            .cf_handle(META_CF)
+            .ok_or_else(|| ManyError::unknown("Missing meta column family."))
+    }
+
+    /// The digest kept in the database, or computed from all entries if there
+    /// is none. A computed digest is only written with the next commit.
+    fn load_digest(&self) -> Result<EntriesDigest, ManyError> {
+        let stored = self
+            .db
+            .get_cf(self.meta()?, DIGEST_KEY)
+            .map_err(ManyError::unknown)?;
+        if let Some(digest) = stored {
+            return digest
+                .as_slice()
+                .try_into()
+                .map_err(|_| ManyError::unknown("Invalid digest in the database."));
+        }
+
+        let mut digest = EntriesDigest::default();
+        for entry in self.db.iterator(IteratorMode::Start) {
+            let (key, value) = entry.map_err(ManyError::unknown)?;
+            xor(&mut digest, entry_hash(&key, &value));
+        }
+        Ok(digest)
+    }
+
+    /// The first committed key strictly greater than `after`.
+    fn db_next_key(&self, after: &[u8]) -> Result<Option<Vec<u8>>, ManyError> {
+        let mut iter = self.db.raw_iterator();
+        iter.seek(after);
+        if iter.key() == Some(after) {
+            iter.next();
+        }
+        iter.status().map_err(ManyError::unknown)?;
+        Ok(iter.key().map(<[u8]>::to_vec))
+    }
+}
+
+impl KvStore for RocksDbStorage {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, ManyError> {
+        match self.pending.get(key) {
+            Some(value) => Ok(value.cloned()),
+            None => self.db.get(key).map_err(ManyError::unknown),
+        }
+    }
+
+    fn set(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<(), ManyError> {
+        self.pending.set(key, value);
+        Ok(())
+    }
+
+    fn del(&mut self, key: &[u8]) -> Result<(), ManyError> {
+        self.pending.del(key);
+        Ok(())
+    }
+
+    fn contains(&self, key: &[u8]) -> bool {
+        self.get(key).map_or(false, |v| v.is_some())
+    }
+
+    fn size(&self, key: &[u8]) -> Option<usize> {
+        Some(self.get(key).ok()??.len())
+    }
+
+    fn hash(&self) -> Result<Vec<u8>, ManyError> {
+        Ok(match self.strategy {
+            HashStrategy::None => Vec::new(),
+            HashStrategy::Xor => self.digest.to_vec(),
+        })
+    }
+
+    fn commit(&mut self) -> Result<(), ManyError> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+
+        let mut digest = self.digest;
+        let mut batch = WriteBatch::default();
+        for (key, value) in self.pending.take() {
+            if self.strategy == HashStrategy::Xor {
+                if let Some(old) = self.db.get(&key).map_err(ManyError::unknown)? {
+                    xor(&mut digest, entry_hash(&key, &old));
+                }
+                if let Some(new) = &value {
+                    xor(&mut digest, entry_hash(&key, new));
+                }
+            }
+            match value {
+                Some(value) => batch.put(key, value),
+                None => batch.delete(key),
+            }
+        }
+        match self.strategy {
+            HashStrategy::None => batch.delete_cf(self.meta()?, DIGEST_KEY),
+            HashStrategy::Xor => batch.put_cf(self.meta()?, DIGEST_KEY, digest),
+        }
+
+        self.db.write(batch).map_err(ManyError::unknown)?;
+        self.digest = digest;
+        Ok(())
+    }
+
+    fn rollback(&mut self) -> Result<(), ManyError> {
+        self.pending.clear();
+        Ok(())
+    }
+
+    fn savepoint(&mut self) -> Result<(), ManyError> {
+        self.pending.savepoint();
+        Ok(())
+    }
+
+    fn release_savepoint(&mut self) -> Result<(), ManyError> {
+        self.pending.release_savepoint();
+        Ok(())
+    }
+
+    fn rollback_to_savepoint(&mut self) -> Result<(), ManyError> {
+        self.pending.rollback_to_savepoint();
+        Ok(())
+    }
+
+    fn next_key(&self, after: &[u8]) -> Result<Option<Vec<u8>>, ManyError> {
+        self.pending
+            .next_key(after, |after| self.db_next_key(after))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open(dir: &tempfile::TempDir, strategy: HashStrategy) -> RocksDbStorage {
+        RocksDbStorage::new(dir.path().join("db"), false, strategy).unwrap()
+    }
+
+    fn keys(store: &impl KvStore) -> Vec<Vec<u8>> {
+        let mut keys = Vec::new();
+        let mut after = Vec::new();
+        while let Some(key) = store.next_key(&after).unwrap() {
+            keys.push(key.clone());
+            after = key;
+        }
+        keys
+    }
+
+    /// The expected digest of `entries`.
+    fn digest(entries: &[(&[u8], &[u8])]) -> Vec<u8> {
+        let mut digest = EntriesDigest::default();
+        for (key, value) in entries {
+            xor(&mut digest, entry_hash(key, value));
+        }
+        digest.to_vec()
+    }
+
+    #[test]
+    fn pending() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut s = open(&dir, HashStrategy::Xor);
+        assert_eq!(s.hash().unwrap(), digest(&[]));
+        s.set(b"a".to_vec(), b"1".to_vec()).unwrap();
+        s.set(b"c".to_vec(), b"3".to_vec()).unwrap();
+        s.commit().unwrap();
+
+        s.del(b"a").unwrap();
+        s.set(b"b".to_vec(), b"2".to_vec()).unwrap();
+        assert_eq!(s.get(b"a").unwrap(), None);
+        assert_eq!(s.get(b"b").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(keys(&s), vec![b"b".to_vec(), b"c".to_vec()]);
+        assert_eq!(s.hash().unwrap(), digest(&[(b"a", b"1"), (b"c", b"3")]));
+
+        s.rollback().unwrap();
+        assert_eq!(keys(&s), vec![b"a".to_vec(), b"c".to_vec()]);
+
+        s.del(b"a").unwrap();
+        s.set(b"b".to_vec(), b"2".to_vec()).unwrap();
+        s.set(b"c".to_vec(), b"4".to_vec()).unwrap();
+        // Deleting a key that doesn't exist changes nothing.
+        s.del(b"d").unwrap();
+        s.commit().unwrap();
+        assert_eq!(keys(&s), vec![b"b".to_vec(), b"c".to_vec()]);
+        assert_eq!(s.hash().unwrap(), digest(&[(b"b", b"2"), (b"c", b"4")]));
+    }
+
+    #[test]
+    fn reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut s = open(&dir, HashStrategy::Xor);
+        s.set(b"a".to_vec(), b"1".to_vec()).unwrap();
+        s.commit().unwrap();
+        let hash = s.hash().unwrap();
+        drop(s);
+
+        let s = open(&dir, HashStrategy::Xor);
+        assert_eq!(s.hash().unwrap(), hash);
+        drop(s);
+
+        // Writes without a hash don't leave a stale one behind.
+        let mut s = open(&dir, HashStrategy::None);
+        assert!(s.hash().unwrap().is_empty());
+        s.set(b"b".to_vec(), b"2".to_vec()).unwrap();
+        s.commit().unwrap();
+        drop(s);
+
+        let s = open(&dir, HashStrategy::Xor);
+        assert_eq!(s.hash().unwrap(), digest(&[(b"a", b"1"), (b"b", b"2")]));
+    }
+}
@@ -0,0 +1,263 @@
+use crate::storage::memory::hash_entries;
+use crate::storage::KvStore;
+use many_error::ManyError;
+use rusqlite::{params, Connection, OptionalExtension};
+use std::cell::RefCell;
+use std::path::Path;
+
+/// A storage in a single SQLite file, with a `kv` table of key and value
+/// blobs. Writes are done in a transaction that is committed on `commit()`.
+/// The hash is computed the same way as `MemoryStorage`.
+pub struct SqliteStorage {
+    // See `MemoryStorage` for why this is a refcell. It is always set while a
+    // transaction is in progress, since the hash is of the committed state.
+    hash: RefCell<Option<Vec<u8>>>,
+    conn: Connection,
+}
+
+impl SqliteStorage {
+    fn open(path: &Path) -> Result<Self, ManyError> {
+        let conn = Connection::open(path).map_err(ManyError::unknown)?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS kv (
+                key BLOB PRIMARY KEY NOT NULL,
+                value BLOB NOT NULL
+            ) WITHOUT ROWID;",
+        )
+        .map_err(ManyError::unknown)?;
+
+        Ok(Self {
+            hash: RefCell::new(None),
+            conn,
+        })
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ManyError> {
+        if !path.as_ref().exists() {
+            return Err(ManyError::unknown(format!(
+                "Storage file '{}' does not exist.",
+                path.as_ref().display()
+            )));
+        }
+        Self::open(path.as_ref())
+    }
+
+    pub fn new(path: impl AsRef<Path>, delete_if_exists: bool) -> Result<Self, ManyError> {
+        if delete_if_exists && path.as_ref().exists() {
+            std::fs::remove_file(path.as_ref()).map_err(ManyError::unknown)?;
+        }
+        Self::open(path.as_ref())
+    }
+
+    /// Start a transaction if none is in progress.
+    fn begin(&mut self) -> Result<(), ManyError> {
+        if self.conn.is_autocommit() {
+            self.hash()?;
+            self.conn
+                .execute_batch("BEGIN")
+                .map_err(ManyError::unknown)?;
+        }
+        Ok(())
+    }
+
+    fn entries_hash(&self) -> Result<Vec<u8>, ManyError> {
+        let mut stmt = self
+            .conn
+            .prepare_cached("SELECT key, value FROM kv ORDER BY key")
+            .map_err(ManyError::unknown)?;
+        let rows = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .map_err(ManyError::unknown)?;
+        let entries = rows
+            .collect::<Result<Vec<(Vec<u8>, Vec<u8>)>, _>>()
+            .map_err(ManyError::unknown)?;
+        Ok(hash_entries(entries))
+    }
+}
+
+impl KvStore for SqliteStorage {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, ManyError> {
+        self.conn
+            .query_row("SELECT value FROM kv WHERE key = ?1", params![key], |row| {
+                row.get(0)
+            })
+            .optional()
+            .map_err(ManyError::unknown)
+    }
+
+    fn set(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<(), ManyError> {
+        self.begin()?;
+        self.conn
+            .execute(
+                "INSERT OR REPLACE INTO kv (key, value) VALUES (?1, ?2)",
+                params![key, value],
+            )
+            .map_err(ManyError::unknown)?;
+        Ok(())
+    }
+
+    fn del(&mut self, key: &[u8]) -> Result<(), ManyError> {
+        self.begin()?;
+        self.conn
+            .execute("DELETE FROM kv WHERE key = ?1", params![key])
+            .map_err(ManyError::unknown)?;
+        Ok(())
+    }
+
+    fn contains(&self, key: &[u8]) -> bool {
+        self.get(key).map_or(false, |v| v.is_some())
+    }
+
+    fn size(&self, key: &[u8]) -> Option<usize> {
+        Some(self.get(key).ok()??.len())
+    }
+
+    fn hash(&self) -> Result<Vec<u8>, ManyError> {
+        if let Some(ref hash) = *self.hash.borrow() {
+            return Ok(hash.to_vec());
+        }
+        let hash = self.entries_hash()?;
+        self.hash.replace(Some(hash.clone()));
+        Ok(hash)
+    }
+
+    fn commit(&mut self) -> Result<(), ManyError> {
+        if !self.conn.is_autocommit() {
+            self.conn
+                .execute_batch("COMMIT")
+                .map_err(ManyError::unknown)?;
+            self.hash.replace(None);
+        }
+        Ok(())
+    }
+
+    fn rollback(&mut self) -> Result<(), ManyError> {
+        if !self.conn.is_autocommit() {
+            self.conn
+                .execute_batch("ROLLBACK")
+                .map_err(ManyError::unknown)?;
+        }
+        Ok(())
+    }
+
+    // Savepoints of the same name nest, the most recent one being used.
+    fn savepoint(&mut self) -> Result<(), ManyError> {
+        self.begin()?;
+        self.conn
+            .execute_batch("SAVEPOINT sp")
+            .map_err(ManyError::unknown)
+    }
+
+    fn release_savepoint(&mut self) -> Result<(), ManyError> {
+        if !self.conn.is_autocommit() {
+            self.conn
+                .execute_batch("RELEASE sp")
+                .map_err(ManyError::unknown)?;
+        }
+        Ok(())
+    }
+
+    fn rollback_to_savepoint(&mut self) -> Result<(), ManyError> {
+        if !self.conn.is_autocommit() {
+            self.conn
+                .execute_batch("ROLLBACK TO sp; RELEASE sp")
+                .map_err(ManyError::unknown)?;
+        }
+        Ok(())
+    }
+
+    fn next_key(&self, after: &[u8]) -> Result<Option<Vec<u8>>, ManyError> {
+        self.conn
+            .query_row(
+                "SELECT key FROM kv WHERE key > ?1 ORDER BY key LIMIT 1",
+                params![after],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(ManyError::unknown)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open() -> (tempfile::TempDir, SqliteStorage) {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = SqliteStorage::new(dir.path().join("kv.db"), true).unwrap();
+        (dir, storage)
+    }
+
+    #[test]
+    fn transaction() {
+        let (_dir, mut s) = open();
+        let empty = s.hash().unwrap();
+        assert!(s.conn.is_autocommit());
+
+        s.set(b"a".to_vec(), b"1".to_vec()).unwrap();
+        assert!(!s.conn.is_autocommit());
+        assert_eq!(s.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(s.hash().unwrap(), empty);
+
+        s.commit().unwrap();
+        assert!(s.conn.is_autocommit());
+        let hash = s.hash().unwrap();
+        assert_eq!(hash, hash_entries([(b"a", b"1")]));
+
+        s.del(b"a").unwrap();
+        s.set(b"b".to_vec(), b"2".to_vec()).unwrap();
+        assert_eq!(s.hash().unwrap(), hash);
+        s.rollback().unwrap();
+        assert_eq!(s.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(s.get(b"b").unwrap(), None);
+        assert_eq!(s.hash().unwrap(), hash);
+    }
+
+    #[test]
+    fn persisted() {
+        let (dir, mut s) = open();
+        s.set(b"a".to_vec(), b"1".to_vec()).unwrap();
+        s.commit().unwrap();
+        s.set(b"b".to_vec(), b"2".to_vec()).unwrap();
+        let hash = s.hash().unwrap();
+        drop(s);
+
+        let s = SqliteStorage::load(dir.path().join("kv.db")).unwrap();
+        assert_eq!(s.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(s.get(b"b").unwrap(), None);
+        assert_eq!(s.hash().unwrap(), hash);
+    }
+
+    #[test]
+    fn next_key() {
+        let (_dir, mut s) = open();
+        s.set(b"b".to_vec(), vec![]).unwrap();
+        s.set(b"a\0".to_vec(), vec![]).unwrap();
+        s.commit().unwrap();
+        s.set(b"a".to_vec(), vec![]).unwrap();
+        s.del(b"b").unwrap();
+
+        assert_eq!(s.next_key(b"").unwrap(), Some(b"a".to_vec()));
+        assert_eq!(s.next_key(b"a").unwrap(), Some(b"a\0".to_vec()));
+        assert_eq!(s.next_key(b"a\0").unwrap(), None);
+    }
+
+    #[test]
+    fn savepoints() {
+        let (_dir, mut s) = open();
+        s.set(b"a".to_vec(), b"1".to_vec()).unwrap();
+        s.savepoint().unwrap();
+        s.set(b"a".to_vec(), b"2".to_vec()).unwrap();
+        s.savepoint().unwrap();
+        s.set(b"b".to_vec(), b"2".to_vec()).unwrap();
+        s.release_savepoint().unwrap();
+        assert_eq!(s.get(b"b").unwrap(), Some(b"2".to_vec()));
+
+        s.rollback_to_savepoint().unwrap();
+        assert_eq!(s.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(s.get(b"b").unwrap(), None);
+
+        s.commit().unwrap();
+        assert_eq!(s.hash().unwrap(), hash_entries([(b"a", b"1")]));
+    }
+}
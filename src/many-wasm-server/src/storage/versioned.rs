@@ -0,0 +1,464 @@
+use crate::storage::KvStore;
+use many_error::ManyError;
+use schemars::JsonSchema;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+
+const HEIGHT_KEY: &[u8] = b"\x00height";
+const EARLIEST_KEY: &[u8] = b"\x00earliest";
+const CURRENT_PREFIX: u8 = 1;
+const HISTORY_PREFIX: u8 = 2;
+const INDEX_PREFIX: u8 = 3;
+
+/// Which old values a `VersionedStorage` keeps.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum PruningPolicy {
+    /// Keep the values of every height.
+    #[default]
+    Nothing,
+
+    /// Only keep enough values to query the given number of recent heights.
+    KeepRecent(u64),
+}
+
+/// The prefix of all keys of a versioned storage in its backend, so they
+/// don't collide with other keys of the backend.
+pub fn namespace(name: &str) -> Vec<u8> {
+    format!("\0versioned/{name}/").into_bytes()
+}
+
+fn current_key(key: &[u8]) -> Vec<u8> {
+    [&[CURRENT_PREFIX], key].concat()
+}
+
+/// History keys are the escaped key (`0x00` becomes `0x00 0xFF`, terminated by
+/// `0x00 0x00`) followed by the height, so they are ordered by key then height.
+fn history_prefix(key: &[u8]) -> Vec<u8> {
+    let mut out = vec![HISTORY_PREFIX];
+    for b in key {
+        out.push(*b);
+        if *b == 0 {
+            out.push(0xFF);
+        }
+    }
+    out.extend_from_slice(&[0, 0]);
+    out
+}
+
+fn history_key(key: &[u8], height: u64) -> Vec<u8> {
+    [history_prefix(key).as_slice(), &height.to_be_bytes()].concat()
+}
+
+fn key_from_history_key(history_key: &[u8]) -> Option<Vec<u8>> {
+    let mut key = Vec::new();
+    let mut bytes = history_key.strip_prefix(&[HISTORY_PREFIX])?.iter();
+    loop {
+        match (bytes.next()?, bytes.as_slice().first()) {
+            (0, Some(0)) => return Some(key),
+            (0, Some(0xFF)) => {
+                key.push(0);
+                bytes.next();
+            }
+            (0, _) => return None,
+            (b, _) => key.push(*b),
+        }
+    }
+}
+
+fn index_key(height: u64, key: &[u8]) -> Vec<u8> {
+    [&[INDEX_PREFIX], height.to_be_bytes().as_slice(), key].concat()
+}
+
+fn encode_value(value: Option<Vec<u8>>) -> Vec<u8> {
+    match value {
+        None => vec![0],
+        Some(v) => [&[1], v.as_slice()].concat(),
+    }
+}
+
+fn decode_value(bytes: Vec<u8>) -> Result<Option<Vec<u8>>, ManyError> {
+    match bytes.split_first() {
+        Some((0, [])) => Ok(None),
+        Some((1, value)) => Ok(Some(value.to_vec())),
+        _ => Err(ManyError::unknown("Invalid history value.")),
+    }
+}
+
+fn read_u64(store: &impl KvStore, key: &[u8]) -> Result<u64, ManyError> {
+    match store.get(key)? {
+        None => Ok(0),
+        Some(bytes) => {
+            Ok(u64::from_be_bytes(bytes.try_into().map_err(|_| {
+                ManyError::unknown("Invalid height in storage.")
+            })?))
+        }
+    }
+}
+
+/// A storage that keeps a commit height and the old value of every key modified
+/// at each height, so the state can be read as of a past height.
+///
+/// Commits that did not modify anything do not increase the height.
+pub struct VersionedStorage<S: KvStore> {
+    inner: S,
+    pruning: PruningPolicy,
+
+    /// The last committed height, and the earliest height that can be queried.
+    height: u64,
+    earliest: u64,
+
+    /// Keys modified since the last commit, whose old value has been recorded.
+    touched: BTreeSet<Vec<u8>>,
+
+    /// The keys touched as of each savepoint. The recorded values themselves
+    /// are restored by the savepoints of the backend.
+    savepoints: Vec<BTreeSet<Vec<u8>>>,
+
+    /// When set, all reads are done as of this height and writes fail.
+    checkout: Option<u64>,
+}
+
+impl<S: KvStore> VersionedStorage<S> {
+    pub fn new(inner: S, pruning: PruningPolicy) -> Result<Self, ManyError> {
+        Ok(Self {
+            height: read_u64(&inner, HEIGHT_KEY)?,
+            earliest: read_u64(&inner, EARLIEST_KEY)?,
+            inner,
+            pruning,
+            touched: BTreeSet::new(),
+            savepoints: Vec::new(),
+            checkout: None,
+        })
+    }
+
+    fn check_writable(&self) -> Result<(), ManyError> {
+        match self.checkout {
+            None => Ok(()),
+            Some(height) => Err(ManyError::unknown(format!(
+                "Storage is read-only at height {height}."
+            ))),
+        }
+    }
+
+    /// Record the value a key had at the current height, before it is
+    /// modified for the first time since the last commit.
+    fn record(&mut self, key: &[u8]) -> Result<(), ManyError> {
+        if self.touched.insert(key.to_vec()) {
+            let old = self.inner.get(&current_key(key))?;
+            self.inner
+                .set(history_key(key, self.height), encode_value(old))?;
+            // The value of index entries is unused.
+            self.inner.set(index_key(self.height, key), vec![1])?;
+        }
+        Ok(())
+    }
+
+    /// The value of a key at a past height. The first value recorded at or
+    /// after that height is the one it had; if none, it never changed since.
+    pub fn get_at(&self, key: &[u8], height: u64) -> Result<Option<Vec<u8>>, ManyError> {
+        if height < self.earliest || height > self.height {
+            return Err(ManyError::unknown(format!(
+                "Height {height} is not available (from {} to {}).",
+                self.earliest, self.height
+            )));
+        }
+
+        let exact = history_key(key, height);
+        if let Some(bytes) = self.inner.get(&exact)? {
+            return decode_value(bytes);
+        }
+
+        let prefix = history_prefix(key);
+        match self.inner.next_key(&exact)? {
+            Some(next) if next.starts_with(&prefix) => match self.inner.get(&next)? {
+                Some(bytes) => decode_value(bytes),
+                None => Ok(None),
+            },
+            _ => self.inner.get(&current_key(key)),
+        }
+    }
+
+    fn next_current_key(&self, after: &[u8]) -> Result<Option<Vec<u8>>, ManyError> {
+        Ok(self
+            .inner
+            .next_key(&current_key(after))?
+            .and_then(|k| k.strip_prefix(&[CURRENT_PREFIX]).map(<[u8]>::to_vec)))
+    }
+
+    /// Any key that existed at a past height either still exists, or has
+    /// a recorded value.
+    fn next_key_at(&self, after: &[u8], height: u64) -> Result<Option<Vec<u8>>, ManyError> {
+        let mut after = after.to_vec();
+        loop {
+            let seek = [history_prefix(&after).as_slice(), &[0xFF; 8]].concat();
+            let from_history = self
+                .inner
+                .next_key(&seek)?
+                .and_then(|k| key_from_history_key(&k));
+            let candidate = match (self.next_current_key(&after)?, from_history) {
+                (Some(a), Some(b)) => a.min(b),
+                (a, b) => match a.or(b) {
+                    Some(k) => k,
+                    None => return Ok(None),
+                },
+            };
+
+            if self.get_at(&candidate, height)?.is_some() {
+                return Ok(Some(candidate));
+            }
+            after = candidate;
+        }
+    }
+
+    /// Remove the recorded values that are only needed for heights that
+    /// should not be kept anymore.
+    fn prune(&mut self) -> Result<(), ManyError> {
+        let cutoff = match self.pruning {
+            PruningPolicy::Nothing => return Ok(()),
+            PruningPolicy::KeepRecent(n) => self.height.saturating_sub(n),
+        };
+        if cutoff <= self.earliest {
+            return Ok(());
+        }
+
+        let mut last = vec![INDEX_PREFIX];
+        while let Some(next) = self.inner.next_key(&last)? {
+            if next.first() != Some(&INDEX_PREFIX) || next.len() < 9 {
+                break;
+            }
+            let height = u64::from_be_bytes(next[1..9].try_into().unwrap());
+            if height >= cutoff {
+                break;
+            }
+            self.inner.del(&history_key(&next[9..], height))?;
+            self.inner.del(&next)?;
+            last = next;
+        }
+
+        self.earliest = cutoff;
+        self.inner
+            .set(EARLIEST_KEY.to_vec(), cutoff.to_be_bytes().to_vec())
+    }
+}
+
+impl<S: KvStore> KvStore for VersionedStorage<S> {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, ManyError> {
+        match self.checkout {
+            None => self.inner.get(&current_key(key)),
+            Some(height) => self.get_at(key, height),
+        }
+    }
+
+    fn set(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<(), ManyError> {
+        self.check_writable()?;
+        self.record(&key)?;
+        self.inner.set(current_key(&key), value)
+    }
+
+    fn del(&mut self, key: &[u8]) -> Result<(), ManyError> {
+        self.check_writable()?;
+        self.record(key)?;
+        self.inner.del(&current_key(key))
+    }
+
+    fn contains(&self, key: &[u8]) -> bool {
+        self.get(key).map_or(false, |v| v.is_some())
+    }
+
+    fn size(&self, key: &[u8]) -> Option<usize> {
+        Some(self.get(key).ok()??.len())
+    }
+
+    fn hash(&self) -> Result<Vec<u8>, ManyError> {
+        self.inner.hash()
+    }
+
+    fn commit(&mut self) -> Result<(), ManyError> {
+        self.savepoints.clear();
+        if !self.touched.is_empty() {
+            self.height += 1;
+            self.touched.clear();
+            self.inner
+                .set(HEIGHT_KEY.to_vec(), self.height.to_be_bytes().to_vec())?;
+            self.prune()?;
+        }
+        self.inner.commit()
+    }
+
+    fn rollback(&mut self) -> Result<(), ManyError> {
+        self.touched.clear();
+        self.savepoints.clear();
+        self.inner.rollback()
+    }
+
+    fn savepoint(&mut self) -> Result<(), ManyError> {
+        self.savepoints.push(self.touched.clone());
+        Ok(())
+    }
+
+    fn release_savepoint(&mut self) -> Result<(), ManyError> {
+        self.savepoints.pop();
+        Ok(())
+    }
+
+    fn rollback_to_savepoint(&mut self) -> Result<(), ManyError> {
+        if let Some(touched) = self.savepoints.pop() {
+            self.touched = touched;
+        }
+        Ok(())
+    }
+
+    fn next_key(&self, after: &[u8]) -> Result<Option<Vec<u8>>, ManyError> {
+        match self.checkout {
+            None => self.next_current_key(after),
+            Some(height) => self.next_key_at(after, height),
+        }
+    }
+
+    fn height(&self) -> Option<u64> {
+        Some(self.height)
+    }
+
+    fn checkout(&mut self, height: u64) -> Result<(), ManyError> {
+        if !self.touched.is_empty() {
+            return Err(ManyError::unknown(
+                "Cannot checkout a height with uncommitted changes.",
+            ));
+        }
+        if height < self.earliest || height > self.height {
+            return Err(ManyError::unknown(format!(
+                "Height {height} is not available (from {} to {}).",
+                self.earliest, self.height
+            )));
+        }
+        self.checkout = Some(height);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::memory::MemoryStorage;
+
+    fn storage(pruning: PruningPolicy) -> VersionedStorage<MemoryStorage> {
+        VersionedStorage::new(MemoryStorage::default(), pruning).unwrap()
+    }
+
+    fn keys(store: &impl KvStore) -> Vec<Vec<u8>> {
+        let mut keys = Vec::new();
+        let mut after = Vec::new();
+        while let Some(key) = store.next_key(&after).unwrap() {
+            keys.push(key.clone());
+            after = key;
+        }
+        keys
+    }
+
+    #[test]
+    fn get_at() {
+        let mut s = storage(PruningPolicy::Nothing);
+        s.set(b"a".to_vec(), b"1".to_vec()).unwrap();
+        s.commit().unwrap();
+        s.set(b"a".to_vec(), b"2".to_vec()).unwrap();
+        s.set(b"b\0".to_vec(), b"3".to_vec()).unwrap();
+        s.commit().unwrap();
+        s.del(b"a").unwrap();
+        s.commit().unwrap();
+
+        assert_eq!(s.height(), Some(3));
+        assert_eq!(s.get_at(b"a", 0).unwrap(), None);
+        assert_eq!(s.get_at(b"a", 1).unwrap(), Some(b"1".to_vec()));
+        assert_eq!(s.get_at(b"a", 2).unwrap(), Some(b"2".to_vec()));
+        assert_eq!(s.get_at(b"a", 3).unwrap(), None);
+        assert_eq!(s.get_at(b"b\0", 1).unwrap(), None);
+        assert_eq!(s.get_at(b"b\0", 3).unwrap(), Some(b"3".to_vec()));
+        assert!(s.get_at(b"a", 4).is_err());
+    }
+
+    #[test]
+    fn commit_without_writes() {
+        let mut s = storage(PruningPolicy::Nothing);
+        s.commit().unwrap();
+        assert_eq!(s.height(), Some(0));
+        s.set(b"a".to_vec(), b"1".to_vec()).unwrap();
+        s.rollback().unwrap();
+        s.commit().unwrap();
+        assert_eq!(s.height(), Some(0));
+        assert_eq!(s.get(b"a").unwrap(), None);
+    }
+
+    #[test]
+    fn next_key_at() {
+        let mut s = storage(PruningPolicy::Nothing);
+        s.set(b"a".to_vec(), b"1".to_vec()).unwrap();
+        s.set(b"c".to_vec(), b"1".to_vec()).unwrap();
+        s.commit().unwrap();
+        s.del(b"a").unwrap();
+        s.set(b"b".to_vec(), b"2".to_vec()).unwrap();
+        s.commit().unwrap();
+
+        assert_eq!(keys(&s), vec![b"b".to_vec(), b"c".to_vec()]);
+        assert_eq!(s.next_key_at(b"", 0).unwrap(), None);
+        assert_eq!(s.next_key_at(b"", 1).unwrap(), Some(b"a".to_vec()));
+        assert_eq!(s.next_key_at(b"a", 1).unwrap(), Some(b"c".to_vec()));
+        assert_eq!(s.next_key_at(b"", 2).unwrap(), Some(b"b".to_vec()));
+    }
+
+    #[test]
+    fn prune() {
+        let mut s = storage(PruningPolicy::KeepRecent(2));
+        for i in 1..=5u8 {
+            s.set(b"a".to_vec(), vec![i]).unwrap();
+            s.commit().unwrap();
+        }
+
+        assert_eq!(s.height(), Some(5));
+        assert!(s.get_at(b"a", 2).is_err());
+        assert_eq!(s.get_at(b"a", 3).unwrap(), Some(vec![3]));
+        assert_eq!(s.get_at(b"a", 5).unwrap(), Some(vec![5]));
+
+        // Only the history of the kept heights is left.
+        assert!(!s.inner.contains(&history_key(b"a", 1)));
+        assert!(!s.inner.contains(&index_key(1, b"a")));
+        assert!(s.inner.contains(&history_key(b"a", 3)));
+
+        // Pruning is kept across reopening.
+        let s = VersionedStorage::new(s.inner, PruningPolicy::KeepRecent(2)).unwrap();
+        assert_eq!((s.earliest, s.height), (3, 5));
+    }
+
+    #[test]
+    fn checkout() {
+        let mut s = storage(PruningPolicy::Nothing);
+        s.set(b"a".to_vec(), b"1".to_vec()).unwrap();
+        s.commit().unwrap();
+        s.set(b"a".to_vec(), b"2".to_vec()).unwrap();
+        assert!(s.checkout(1).is_err());
+        s.commit().unwrap();
+
+        assert!(s.checkout(3).is_err());
+        s.checkout(1).unwrap();
+        assert_eq!(s.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(keys(&s), vec![b"a".to_vec()]);
+        assert!(s.set(b"a".to_vec(), b"3".to_vec()).is_err());
+        assert!(s.del(b"a").is_err());
+
+        s.checkout(0).unwrap();
+        assert_eq!(s.get(b"a").unwrap(), None);
+        assert!(keys(&s).is_empty());
+    }
+
+    #[test]
+    fn history_keys() {
+        for key in [&b""[..], b"a", b"\0", b"a\0b", b"\0\xFF"] {
+            assert_eq!(
+                key_from_history_key(&history_key(key, 7)).as_deref(),
+                Some(key)
+            );
+        }
+        assert!(history_key(b"a", u64::MAX) < history_key(b"a\0", 0));
+        assert!(history_key(b"a\0", u64::MAX) < history_key(b"a\x01", 0));
+    }
+}
@@ -1,117 +1,498 @@
 use crate::abi;
-use crate::config::ModuleConfig;
+use crate::config::{ModuleConfig, ModuleLimits, SingleModuleConfig, StorageAccess};
+use crate::signature::ModuleVerifier;
 use crate::storage::StorageLibrary;
 use abi::wasi_snapshot_preview1::create_wasi_ctx;
 use anyhow::anyhow;
 use many_error::ManyError;
+use many_identity::Address;
 use many_protocol::RequestMessage;
-use state::WasmContext;
-use std::borrow::Cow;
-use std::collections::BTreeMap;
-use tracing::debug;
-use wasmtime::{Engine, Linker, Module, Store};
+use minicbor::bytes::ByteVec;
+use serde_json::Value;
+use sha3::{Digest, Sha3_256};
+use state::{CallContext, WasmContext};
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+use tracing::{debug, error, info};
+use wasmtime::{
+    AsContext, AsContextMut, Config, Engine, ExternType, ImportType, Linker, Module, Store,
+};
 
+pub mod deploy;
 pub mod state;
 
-#[derive(Default)]
-struct ModuleLibrary {
-    endpoints: BTreeMap<String, usize>,
+#[derive(Clone)]
+pub struct LoadedModule {
+    pub name: String,
+    pub module: Module,
+    pub storages: BTreeMap<String, StorageAccess>,
+
+    /// Hash of the module bytes, used to skip recompiling unchanged modules.
+    pub hash: Vec<u8>,
+
+    /// Settings from the module configuration. Deployed modules use the
+    /// defaults: all endpoints served under their own name, without limits.
+    pub endpoints: Option<BTreeSet<String>>,
+    pub rename: BTreeMap<String, String>,
+    pub limits: ModuleLimits,
+    pub arg: Value,
+}
+
+#[derive(Clone, Default)]
+pub struct ModuleLibrary {
+    /// Served endpoint names, to the module index and the exported name.
+    endpoints: BTreeMap<String, (usize, String)>,
     names: BTreeMap<String, usize>,
-    modules: Vec<Module>,
+    attributes: BTreeSet<u32>,
+    modules: Vec<LoadedModule>,
+}
+
+/// The endpoints a module exports, as functions named `endpoint <name>`.
+pub fn exported_endpoints(module: &Module) -> BTreeSet<String> {
+    module
+        .exports()
+        .filter(|e| e.ty().func().is_some() && e.name().starts_with("endpoint "))
+        .map(|e| e.name()[9..].to_string())
+        .collect()
 }
 
 impl ModuleLibrary {
-    pub fn add(&mut self, module: Module, name: Cow<str>) -> Result<(), anyhow::Error> {
-        let endpoints = module
-            .exports()
+    pub fn add(&mut self, loaded: LoadedModule) -> Result<(), anyhow::Error> {
+        let exported = exported_endpoints(&loaded.module);
+
+        let name = &loaded.name;
+        for ep in loaded
+            .endpoints
+            .iter()
+            .flatten()
+            .chain(loaded.rename.keys())
+        {
+            if !exported.contains(ep) {
+                return Err(anyhow!("Module {name} does not export endpoint {ep}."));
+            }
+        }
+
+        // Pairs of served and exported endpoint names.
+        let endpoints = exported
             .into_iter()
-            .filter(|e| e.ty().func().is_some() && e.name().starts_with("endpoint "))
-            .map(|e| e.name()[9..].to_string())
-            .collect::<Vec<String>>();
+            .filter(|ep| loaded.endpoints.as_ref().map_or(true, |a| a.contains(ep)))
+            .map(|ep| {
+                (
+                    loaded
+                        .rename
+                        .get(&ep)
+                        .cloned()
+                        .unwrap_or_else(|| ep.clone()),
+                    ep,
+                )
+            })
+            .collect::<Vec<(String, String)>>();
+
+        // Attributes are declared by exporting (empty) functions named
+        // `attribute <id>`.
+        let attributes = loaded
+            .module
+            .exports()
+            .filter_map(|e| e.name().strip_prefix("attribute "))
+            .map(|id| {
+                id.parse::<u32>()
+                    .map_err(|_| anyhow!("Invalid attribute id: {id}"))
+            })
+            .collect::<Result<Vec<u32>, _>>()?;
 
-        debug!("Adding module: endpoints = {endpoints:?}");
+        debug!("Adding module: endpoints = {endpoints:?}, attributes = {attributes:?}");
 
-        for ep in endpoints.iter() {
+        for (ep, _) in endpoints.iter() {
             if self.endpoints.contains_key(ep) {
                 return Err(anyhow!("Endpoint {ep} already registered."));
             }
         }
 
+        let name = loaded.name.clone();
+        if self.names.contains_key(&name) {
+            return Err(anyhow!("Module {name} already registered."));
+        }
+
         let idx = self.modules.len();
-        self.modules.push(module);
-        for ep in endpoints {
-            self.endpoints.insert(ep, idx);
+        self.modules.push(loaded);
+        for (ep, exported) in endpoints {
+            self.endpoints.insert(ep, (idx, exported));
         }
-        self.names.insert(name.into_owned(), idx);
+        self.attributes.extend(attributes);
+        self.names.insert(name, idx);
 
         Ok(())
     }
 
-    pub fn by_endpoint(&self, endpoint: &str) -> Option<&Module> {
-        let idx = self.endpoints.get(endpoint)?;
-        self.modules.get(*idx)
+    /// Add a module, replacing any module with the same name. Fails (leaving
+    /// the library unchanged) if its endpoints conflict with another module.
+    pub fn replace(&mut self, loaded: LoadedModule) -> Result<(), anyhow::Error> {
+        let mut library = ModuleLibrary::default();
+        for m in self.modules.iter().filter(|m| m.name != loaded.name) {
+            library.add(m.clone())?;
+        }
+        library.add(loaded)?;
+
+        *self = library;
+        Ok(())
+    }
+
+    /// The module serving an endpoint, with the name it exports it under.
+    pub fn by_endpoint(&self, endpoint: &str) -> Option<(&LoadedModule, &str)> {
+        let (idx, exported) = self.endpoints.get(endpoint)?;
+        Some((self.modules.get(*idx)?, exported.as_str()))
     }
 
-    pub fn by_name(&self, name: &str) -> Option<&Module> {
+    pub fn by_name(&self, name: &str) -> Option<&LoadedModule> {
         let idx = self.names.get(name)?;
         self.modules.get(*idx)
     }
 
-    pub fn iter(&self) -> impl Iterator<Item = &Module> {
+    pub fn iter(&self) -> impl Iterator<Item = &LoadedModule> {
         self.modules.iter()
     }
+
+    pub fn endpoints(&self) -> impl Iterator<Item = &String> {
+        self.endpoints.keys()
+    }
+
+    pub fn attributes(&self) -> &BTreeSet<u32> {
+        &self.attributes
+    }
+}
+
+/// Call an export of a module, taking and returning nothing, in a new call
+/// frame. Storage access is checked against the grants of the module, and the
+/// fuel available is capped by its limits for the duration of the call.
+///
+/// Fails without calling the export if the frame cannot be entered. Otherwise
+/// returns the result of the call and the context the module left.
+fn call_in_frame(
+    mut store: impl AsContextMut<Data = WasmContext>,
+    loaded: &LoadedModule,
+    export: &str,
+    context: CallContext,
+) -> Result<(Result<(), anyhow::Error>, CallContext), ManyError> {
+    store
+        .as_context_mut()
+        .data_mut()
+        .enter(&loaded.name, context)?;
+
+    // Withhold the fuel above the module limit for the duration of the call.
+    let withheld = match loaded.limits.fuel {
+        Some(limit) => {
+            let mut ctx = store.as_context_mut();
+            let withheld = ctx.consume_fuel(0).and_then(|remaining| {
+                let withheld = remaining.saturating_sub(limit);
+                ctx.consume_fuel(withheld).map(|_| withheld)
+            });
+            match withheld {
+                Ok(withheld) => withheld,
+                Err(e) => {
+                    // Nothing ran, so the only error that matters is this one.
+                    let _ = ctx.data_mut().leave(true);
+                    return Err(ManyError::unknown(e));
+                }
+            }
+        }
+        None => 0,
+    };
+
+    let linker = store.as_context().data().linker();
+    let result = linker
+        .instantiate(&mut store, &loaded.module)
+        .and_then(|instance| instance.get_typed_func::<(), (), _>(&mut store, export))
+        .and_then(|func| func.call(&mut store, ()));
+
+    let context = store.as_context_mut().data_mut().leave(result.is_err());
+    if withheld > 0 {
+        store
+            .as_context_mut()
+            .add_fuel(withheld)
+            .map_err(ManyError::unknown)?;
+    }
+    Ok((result, context?))
+}
+
+/// Run an endpoint in a new call frame. This is used both for requests coming
+/// from the executor and for nested calls made by modules through `many::call`,
+/// in which case `store` is the caller of the host function.
+pub fn run_endpoint(
+    mut store: impl AsContextMut<Data = WasmContext>,
+    request: RequestMessage,
+) -> Result<Vec<u8>, ManyError> {
+    let endpoint = request.method.to_string();
+    let modules = store.as_context().data().modules();
+    let (loaded, exported) = modules
+        .by_endpoint(&endpoint)
+        .ok_or_else(|| ManyError::unknown("Endpoint not found"))?;
+
+    let (result, context) = call_in_frame(
+        &mut store,
+        loaded,
+        &format!("endpoint {exported}"),
+        CallContext::ManyRequest(request, None),
+    )?;
+    match (result, context.into_response()) {
+        (Err(t), _) => Err(ManyError::unknown(format!("trapped: {t}"))),
+        (Ok(()), Ok(x)) => x,
+        (Ok(()), Err(e)) => Err(ManyError::unknown(e)),
+    }
+}
+
+/// Run the `init` or `migrate` export of a registered module, with `arg` as its
+/// argument.
+fn run_export(
+    mut store: impl AsContextMut<Data = WasmContext>,
+    loaded: &LoadedModule,
+    export: &str,
+    arg: Vec<u8>,
+) -> Result<(), ManyError> {
+    let (result, _) = call_in_frame(&mut store, loaded, export, CallContext::Initialize(arg))?;
+    result.map_err(|t| ManyError::unknown(format!("trapped: {t}")))
+}
+
+/// Fuel given to every call from outside the engine. Limits are per module.
+const MAX_FUEL: u64 = u64::MAX / 4;
+
+/// Reset the fuel of the store to `MAX_FUEL`.
+fn refuel(store: &mut Store<WasmContext>) -> Result<(), anyhow::Error> {
+    let remaining = store.consume_fuel(0)?;
+    store.add_fuel(MAX_FUEL.saturating_sub(remaining))
 }
 
 pub struct WasmEngine {
     store: Store<WasmContext>,
-    linker: Linker<WasmContext>,
-    modules: ModuleLibrary,
+    deploy_storage: Option<String>,
+    verifier: ModuleVerifier,
 }
 
 impl WasmEngine {
-    pub fn new(storage: StorageLibrary) -> Result<Self, anyhow::Error> {
-        let engine = Engine::default();
-        let mut store = Store::new(&engine, WasmContext::new(storage, create_wasi_ctx()));
-        let mut linker = Linker::new(store.engine());
+    pub fn new(storage: StorageLibrary, address: Address) -> Result<Self, anyhow::Error> {
+        let mut config = Config::new();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config)?;
+        let mut linker = Linker::new(&engine);
         abi::link(&mut linker)?;
 
+        let mut store = Store::new(
+            &engine,
+            WasmContext::new(storage, create_wasi_ctx(), address, Arc::new(linker)),
+        );
+        refuel(&mut store)?;
+
         Ok(Self {
             store,
-            linker,
-            modules: ModuleLibrary::default(),
+            deploy_storage: None,
+            verifier: ModuleVerifier::default(),
         })
     }
 
-    pub fn add_module_config(&mut self, config: ModuleConfig) -> Result<(), anyhow::Error> {
-        for ref config in config {
-            let module: Module = Module::from_file(self.store.engine(), &config.path)
-                .map_err(|e| anyhow!("{}", e))?;
+    pub fn modules(&self) -> Arc<ModuleLibrary> {
+        self.store.data().modules()
+    }
+
+    pub fn storage(&self) -> &StorageLibrary {
+        self.store.data().storage_library()
+    }
+
+    pub fn set_module_verifier(&mut self, verifier: ModuleVerifier) {
+        self.verifier = verifier;
+    }
 
-            // Instantiate at least once to optimize.
-            self.linker.instantiate(&mut self.store, &module)?;
+    /// Compile a module without registering it.
+    pub fn compile(&self, bytes: &[u8]) -> Result<Module, anyhow::Error> {
+        Module::new(self.store.engine(), bytes).map_err(|e| anyhow!("{}", e))
+    }
 
-            self.modules.add(module, config.name())?;
+    /// Check that the host provides an import, with the same type.
+    pub fn check_import(&mut self, import: &ImportType) -> Result<(), anyhow::Error> {
+        let linker = self.store.data().linker();
+        let provided = linker
+            .get(&mut self.store, import.module(), import.name())
+            .ok_or_else(|| anyhow!("Not provided by the host."))?;
+        let (provided, expected) = (provided.ty(&self.store), import.ty());
+        match (&provided, &expected) {
+            (ExternType::Func(p), ExternType::Func(e)) if p == e => Ok(()),
+            _ => Err(anyhow!(
+                "Type mismatch: expected {expected:?}, provided {provided:?}."
+            )),
+        }
+    }
+
+    /// Read a module file and check its signature.
+    fn read_module(&self, config: &SingleModuleConfig) -> Result<Vec<u8>, anyhow::Error> {
+        let bytes = std::fs::read(&config.path)
+            .map_err(|e| anyhow!("Could not read {}: {e}", config.path.display()))?;
+        let signature = config
+            .signature
+            .as_ref()
+            .map(hex::decode)
+            .transpose()
+            .map_err(|e| anyhow!("Invalid signature: {e}"))?;
+
+        self.verifier.check(
+            &config.name(),
+            Some(&config.path),
+            &bytes,
+            signature.as_deref(),
+        )?;
+        Ok(bytes)
+    }
+
+    /// Compile a module from its bytes. If `previous` contains a module of the
+    /// same name with the same bytes, its compiled module is reused.
+    fn compile_bytes(
+        &mut self,
+        name: String,
+        bytes: &[u8],
+        storages: BTreeMap<String, StorageAccess>,
+        previous: Option<&ModuleLibrary>,
+    ) -> Result<LoadedModule, anyhow::Error> {
+        let hash = Sha3_256::digest(bytes).to_vec();
+
+        let module = match previous
+            .and_then(|p| p.by_name(&name))
+            .filter(|m| m.hash == hash)
+        {
+            Some(existing) => existing.module.clone(),
+            None => {
+                let module =
+                    Module::new(self.store.engine(), bytes).map_err(|e| anyhow!("{}", e))?;
+
+                // Instantiate at least once to optimize.
+                self.store
+                    .data()
+                    .linker()
+                    .instantiate(&mut self.store, &module)?;
+                module
+            }
+        };
+
+        Ok(LoadedModule {
+            name,
+            module,
+            storages,
+            hash,
+            endpoints: None,
+            rename: BTreeMap::new(),
+            limits: ModuleLimits::default(),
+            arg: Value::Null,
+        })
+    }
+
+    fn compile_module(
+        &mut self,
+        config: &SingleModuleConfig,
+        previous: Option<&ModuleLibrary>,
+    ) -> Result<LoadedModule, anyhow::Error> {
+        let bytes = self.read_module(config)?;
+        let mut loaded = self.compile_bytes(
+            config.name().into_owned(),
+            &bytes,
+            config.storages.clone(),
+            previous,
+        )?;
+        loaded.endpoints = config.endpoints.clone();
+        loaded.rename = config.rename.clone();
+        loaded.limits = config.limits;
+        loaded.arg = config.arg.clone();
+        Ok(loaded)
+    }
+
+    pub fn add_module_config(&mut self, config: ModuleConfig) -> Result<(), anyhow::Error> {
+        for ref config in config.into_iter().filter(|c| c.enabled) {
+            let loaded = self.compile_module(config, None)?;
+            self.store.data_mut().modules_mut().add(loaded)?;
         }
 
         Ok(())
     }
 
+    /// Build a new module library from `config` and swap it with the current
+    /// one. Modules whose bytes did not change are not recompiled. Deployed
+    /// modules stay active.
+    /// If any module fails to load, the current library is kept and the errors
+    /// are returned per module name.
+    pub fn reload_module_config(
+        &mut self,
+        config: ModuleConfig,
+    ) -> Result<Vec<String>, BTreeMap<String, String>> {
+        let previous = self.store.data().modules();
+        let mut library = ModuleLibrary::default();
+        let mut errors = BTreeMap::new();
+
+        for ref config in config.into_iter().filter(|c| c.enabled) {
+            let name = config.name().into_owned();
+            if let Err(e) = self
+                .compile_module(config, Some(&previous))
+                .and_then(|loaded| library.add(loaded))
+            {
+                errors.insert(name, e.to_string());
+            }
+        }
+
+        if let Err(e) = self.add_deployed_modules(&mut library, Some(&previous)) {
+            errors.insert("<deployed>".to_string(), e.to_string());
+        }
+
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+
+        let names: Vec<String> = library.iter().map(|m| m.name.clone()).collect();
+        self.store.data_mut().set_modules(library);
+        if let Err(e) = self.migrate_modules() {
+            self.store.data_mut().set_modules((*previous).clone());
+            return Err(BTreeMap::from([("<migrate>".to_string(), e.to_string())]));
+        }
+        info!("Reloaded modules: {names:?}");
+        Ok(names)
+    }
+
     pub fn init(&mut self, init: ModuleConfig) -> Result<(), anyhow::Error> {
+        let linker = self.store.data().linker();
         // First, initialize with the init modules.
-        for ref config in init {
-            let module: Module = Module::from_file(self.store.engine(), &config.path)
-                .map_err(|e| anyhow!("{}", e))?;
+        for ref config in init.into_iter().filter(|c| c.enabled) {
+            let bytes = self.read_module(config)?;
+            let module: Module =
+                Module::new(self.store.engine(), bytes).map_err(|e| anyhow!("{}", e))?;
 
             // Instantiate it at least once.
-            self.linker.instantiate(&mut self.store, &module)?;
+            linker.instantiate(&mut self.store, &module)?;
+
+            let payload = ByteVec::from(config.arg.to_string().into_bytes());
+            let _: () = self
+                .call_method(&module, "init", (), payload)
+                .map_err(|e| anyhow!("{e}"))?;
 
-            let payload = config.arg.to_string();
-            let _: () = self.call_method(&module, "init", (), payload.into_bytes())?;
+            // Commit after each module, so the next ones see its state in
+            // storage hashes.
+            self.store
+                .data_mut()
+                .storage_library_mut()
+                .commit()
+                .map_err(|e| anyhow!("{e}"))?;
         }
 
-        // Then, call all modules registered.
+        // Then, call the `init` export of all modules registered with their `arg`,
+        // with the same storage grants and limits as their endpoints.
+        let modules = self.store.data().modules();
+        for loaded in modules.iter() {
+            if loaded.module.get_export("init").is_some() {
+                info!(module = loaded.name.as_str(), "Initializing module.");
+                let payload = ByteVec::from(loaded.arg.to_string().into_bytes());
+                let arg = minicbor::to_vec(payload).map_err(|e| anyhow!("{e}"))?;
+                refuel(&mut self.store)?;
+                run_export(&mut self.store, loaded, "init", arg).map_err(|e| anyhow!("{e}"))?;
+            }
+        }
 
-        Ok(())
+        self.store
+            .data_mut()
+            .storage_library_mut()
+            .commit()
+            .map_err(|e| anyhow!("{e}"))
     }
 
     fn call_method<Params, Results, Payload>(
@@ -126,12 +507,17 @@ impl WasmEngine {
         Results: wasmtime::WasmResults,
         Payload: minicbor::Encode<()>,
     {
+        refuel(&mut self.store).map_err(ManyError::unknown)?;
         self.store
             .data_mut()
-            .set_payload(minicbor::to_vec(payload).map_err(ManyError::serialization_error)?);
+            .set_call_context(CallContext::Initialize(
+                minicbor::to_vec(payload).map_err(ManyError::serialization_error)?,
+            ));
 
         let instance = self
-            .linker
+            .store
+            .data()
+            .linker()
             .instantiate(&mut self.store, module)
             .expect("Could not instantiate");
 
@@ -139,34 +525,239 @@ impl WasmEngine {
             .get_typed_func::<Params, Results, _>(&mut self.store, name)
             .map_err(|e| ManyError::unknown(e))?;
 
-        func.call(&mut self.store, args)
-            .map_err(|e| ManyError::unknown(e))
+        let result = func
+            .call(&mut self.store, args)
+            .map_err(|e| ManyError::unknown(e));
+        self.store.data_mut().reset();
+        result
     }
 
+    /// Call an endpoint, committing all storages if it succeeded and rolling
+    /// them back otherwise.
     pub fn call_endpoint(&mut self, message: &RequestMessage) -> Result<Vec<u8>, ManyError> {
-        let endpoint = message.method.to_string();
-        self.store.data_mut().set_request(message.clone());
+        let result = self.call_endpoint_uncommitted(message);
+        let storage = self.store.data_mut().storage_library_mut();
+        match result {
+            Ok(data) => {
+                storage.commit()?;
+                Ok(data)
+            }
+            Err(e) => {
+                if let Err(rollback) = storage.rollback() {
+                    error!(error = %rollback, "Could not roll back storages.");
+                }
+                Err(e)
+            }
+        }
+    }
 
-        let instance = self
-            .linker
-            .instantiate(
-                &mut self.store,
-                self.modules
-                    .by_endpoint(&endpoint)
-                    .ok_or_else(|| ManyError::unknown("Endpoint not found"))?,
-            )
-            .expect("Could not instantiate.");
+    /// Call an endpoint without committing storages. Its writes are pending
+    /// until the next commit, and lost if the engine is dropped before.
+    pub fn call_endpoint_uncommitted(
+        &mut self,
+        message: &RequestMessage,
+    ) -> Result<Vec<u8>, ManyError> {
+        refuel(&mut self.store).map_err(ManyError::unknown)?;
+        run_endpoint(&mut self.store, message.clone())
+    }
+}
 
-        let func = instance
-            .get_typed_func::<(), (), _>(&mut self.store, &format!("endpoint {}", endpoint))
-            .map_err(|e| ManyError::unknown(e))?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{KvStore, StorageRef};
 
-        func.call(&mut self.store, ())
-            .map_err(|e| ManyError::unknown(e))?;
+    const GOOD: &str = r#"
+        (module
+          (import "many" "payload_size" (func (result i32)))
+          (import "many" "payload_copy" (func (param i32 i32) (result i32)))
+          (import "store" "get" (func (param i32 i32 i32 i32 i32) (result i32)))
+          (import "wasi_snapshot_preview1" "proc_exit" (func (param i32)))
+          (memory (export "memory") 1)
+          (func (export "init"))
+          (func (export "endpoint test.echo")))
+    "#;
 
-        match self.store.data_mut().reset() {
-            Ok(x) => x,
-            Err(t) => Err(ManyError::unknown(format!("trapped: {}", t.to_string()))),
+    const BAD: &str = r#"
+        (module
+          (import "many" "payload_size" (func (param i32) (result i32)))
+          (import "many" "bogus" (func))
+          (import "other" "payload_copy" (func (param i32 i32) (result i32))))
+    "#;
+
+    fn imports(engine: &mut WasmEngine, wat: &str) -> BTreeMap<String, bool> {
+        let module = engine.compile(&wat::parse_str(wat).unwrap()).unwrap();
+        module
+            .imports()
+            .map(|i| {
+                let name = format!("{}.{}", i.module(), i.name());
+                (name, engine.check_import(&i).is_ok())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn check_import() {
+        let mut engine = WasmEngine::new(StorageLibrary::new(), Address::anonymous()).unwrap();
+
+        let good = imports(&mut engine, GOOD);
+        assert_eq!(good.len(), 4);
+        assert!(good.values().all(|ok| *ok), "{good:?}");
+
+        let bad = imports(&mut engine, BAD);
+        assert_eq!(
+            bad,
+            BTreeMap::from([
+                ("many.bogus".to_string(), false),
+                ("many.payload_size".to_string(), false),
+                ("other.payload_copy".to_string(), false),
+            ])
+        );
+    }
+
+    #[test]
+    fn endpoints() {
+        let engine = WasmEngine::new(StorageLibrary::new(), Address::anonymous()).unwrap();
+        let module = engine.compile(&wat::parse_str(GOOD).unwrap()).unwrap();
+        assert_eq!(
+            exported_endpoints(&module),
+            BTreeSet::from(["test.echo".to_string()])
+        );
+    }
+
+    const CALLEE: &str = r#"
+        (module
+          (import "store" "storage" (func $storage (param i32 i32) (result i32)))
+          (import "store" "set" (func $set (param i32 i32 i32 i32 i32)))
+          (import "many" "error_create" (func $error_create (param i32) (result i32)))
+          (import "many" "return_error" (func $return_error (param i32)))
+          (import "many" "return_data" (func $return_data (param i32 i32)))
+          (memory (export "memory") 1)
+          (data (i32.const 0) "test")
+          (data (i32.const 16) "b")
+          (data (i32.const 17) "c")
+          (func (export "endpoint callee.fail")
+            (call $set (call $storage (i32.const 0) (i32.const 4))
+              (i32.const 16) (i32.const 1) (i32.const 16) (i32.const 1))
+            (call $return_error (call $error_create (i32.const 1))))
+          (func (export "endpoint callee.ok")
+            (call $set (call $storage (i32.const 0) (i32.const 4))
+              (i32.const 17) (i32.const 1) (i32.const 17) (i32.const 1))
+            (call $return_data (i32.const 0) (i32.const 0))))
+    "#;
+
+    const CALLER: &str = r#"
+        (module
+          (import "store" "storage" (func $storage (param i32 i32) (result i32)))
+          (import "store" "set" (func $set (param i32 i32 i32 i32 i32)))
+          (import "many" "call" (func $call (param i32 i32 i32 i32) (result i32)))
+          (import "many" "return_data" (func $return_data (param i32 i32)))
+          (memory (export "memory") 1)
+          (data (i32.const 0) "test")
+          (data (i32.const 16) "a")
+          (data (i32.const 32) "callee.fail")
+          (data (i32.const 48) "callee.ok")
+          (func (export "endpoint caller.run")
+            (call $set (call $storage (i32.const 0) (i32.const 4))
+              (i32.const 16) (i32.const 1) (i32.const 16) (i32.const 1))
+            (drop (call $call (i32.const 32) (i32.const 11) (i32.const 0) (i32.const 0)))
+            (drop (call $call (i32.const 48) (i32.const 9) (i32.const 0) (i32.const 0)))
+            (call $return_data (i32.const 0) (i32.const 0))))
+    "#;
+
+    /// An engine with a single memory storage, `test`.
+    fn engine() -> WasmEngine {
+        let config = serde_json::from_value(serde_json::json!([
+            { "type": "Memory", "name": "test" },
+        ]))
+        .unwrap();
+        let storage = StorageLibrary::create(config, "", false).unwrap();
+        WasmEngine::new(storage, Address::anonymous()).unwrap()
+    }
+
+    fn add_module(engine: &mut WasmEngine, name: &str, wat: &str) {
+        let storages = BTreeMap::from([("test".to_string(), StorageAccess::ReadWrite)]);
+        let bytes = wat::parse_str(wat).unwrap();
+        let loaded = engine
+            .compile_bytes(name.to_string(), &bytes, storages, None)
+            .unwrap();
+        engine.store.data_mut().modules_mut().add(loaded).unwrap();
+    }
+
+    fn test_storage(engine: &WasmEngine) -> &StorageRef {
+        engine.storage().get("test").unwrap()
+    }
+
+    #[test]
+    fn failed_call_is_discarded() {
+        let mut engine = engine();
+        add_module(&mut engine, "caller", CALLER);
+        add_module(&mut engine, "callee", CALLEE);
+
+        let request = RequestMessage {
+            method: "caller.run".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(engine.call_endpoint(&request).unwrap(), Vec::<u8>::new());
+
+        let storage = test_storage(&engine);
+        assert_eq!(storage.get(b"a").unwrap(), Some(b"a".to_vec()));
+        assert_eq!(storage.get(b"b").unwrap(), None);
+        assert_eq!(storage.get(b"c").unwrap(), Some(b"c".to_vec()));
+    }
+
+    #[test]
+    fn failed_frame_is_discarded() {
+        let mut engine = engine();
+        let mut storage = test_storage(&engine).clone();
+        let ctx = engine.store.data_mut();
+
+        ctx.enter("a", CallContext::None).unwrap();
+        storage.set(b"a".to_vec(), vec![1]).unwrap();
+        ctx.enter("b", CallContext::None).unwrap();
+        storage.set(b"a".to_vec(), vec![2]).unwrap();
+        storage.set(b"b".to_vec(), vec![2]).unwrap();
+        ctx.leave(true).unwrap();
+
+        assert_eq!(storage.get(b"a").unwrap(), Some(vec![1]));
+        assert_eq!(storage.get(b"b").unwrap(), None);
+
+        ctx.enter("b", CallContext::None).unwrap();
+        storage.set(b"b".to_vec(), vec![3]).unwrap();
+        ctx.leave(false).unwrap();
+        ctx.leave(false).unwrap();
+        assert_eq!(storage.get(b"b").unwrap(), Some(vec![3]));
+    }
+
+    #[test]
+    fn reentrancy() {
+        let mut engine = engine();
+        let ctx = engine.store.data_mut();
+
+        ctx.enter("a", CallContext::None).unwrap();
+        ctx.enter("b", CallContext::None).unwrap();
+        assert!(ctx.enter("a", CallContext::None).is_err());
+        assert!(ctx.enter("b", CallContext::None).is_err());
+        assert_eq!(ctx.current_module(), Some("b"));
+
+        ctx.leave(false).unwrap();
+        ctx.enter("b", CallContext::None).unwrap();
+        ctx.leave(false).unwrap();
+        ctx.leave(false).unwrap();
+        assert_eq!(ctx.current_module(), None);
+    }
+
+    #[test]
+    fn max_call_depth() {
+        let mut engine = engine();
+        let ctx = engine.store.data_mut();
+
+        for i in 0..state::MAX_CALL_DEPTH {
+            ctx.enter(&format!("m{i}"), CallContext::None).unwrap();
         }
+        assert!(ctx.enter("last", CallContext::None).is_err());
+
+        ctx.leave(false).unwrap();
+        ctx.enter("last", CallContext::None).unwrap();
     }
 }
@@ -0,0 +1,245 @@
+use crate::config::StorageAccess;
+use crate::storage::{KvStore, StorageRef};
+use crate::wasm_engine::{refuel, run_export, LoadedModule, ModuleLibrary, WasmEngine};
+use anyhow::anyhow;
+use many_error::ManyError;
+use minicbor::bytes::ByteVec;
+use minicbor::{Decode, Encode};
+use std::collections::BTreeMap;
+use tracing::{error, info};
+
+const ACTIVE_KEY: &[u8] = b"active";
+
+fn key_for_code(hash: &[u8]) -> Vec<u8> {
+    [b"code/".as_slice(), hash].concat()
+}
+
+fn key_for_migrated(hash: &[u8]) -> Vec<u8> {
+    [b"migrated/".as_slice(), hash].concat()
+}
+
+/// Arguments of the `wasm.deploy` endpoint.
+#[derive(Encode, Decode)]
+#[cbor(map)]
+pub struct DeployArgs {
+    #[n(0)]
+    pub name: String,
+
+    #[n(1)]
+    #[cbor(with = "minicbor::bytes")]
+    pub code: Vec<u8>,
+
+    #[n(2)]
+    pub storages: Option<BTreeMap<String, StorageAccess>>,
+
+    /// Detached COSE signature of the code.
+    #[n(3)]
+    pub signature: Option<ByteVec>,
+}
+
+/// A deployed module that is active, as recorded in the deploy storage.
+#[derive(Clone, Encode, Decode)]
+#[cbor(map)]
+pub struct ActiveModule {
+    #[n(0)]
+    #[cbor(with = "minicbor::bytes")]
+    pub hash: Vec<u8>,
+
+    #[n(1)]
+    pub storages: BTreeMap<String, StorageAccess>,
+}
+
+impl WasmEngine {
+    /// Set the storage used to record deployed modules. Without one, the
+    /// `wasm.deploy` endpoint is disabled.
+    pub fn set_deploy_storage(&mut self, name: Option<String>) {
+        self.deploy_storage = name;
+    }
+
+    fn deploy_storage(&self) -> Result<StorageRef, ManyError> {
+        let name = self
+            .deploy_storage
+            .as_ref()
+            .ok_or_else(|| ManyError::unknown("Module deployment is not enabled."))?;
+        self.store
+            .data()
+            .storage_library()
+            .get(name)
+            .cloned()
+            .ok_or_else(|| ManyError::unknown(format!("Unknown deploy storage {name}.")))
+    }
+
+    fn active_modules(storage: &StorageRef) -> Result<BTreeMap<String, ActiveModule>, ManyError> {
+        match storage.get(ACTIVE_KEY)? {
+            Some(bytes) => minicbor::decode(&bytes).map_err(ManyError::deserialization_error),
+            None => Ok(BTreeMap::new()),
+        }
+    }
+
+    /// Add all deployed modules that are active to `library`, replacing modules
+    /// of the same name.
+    pub(super) fn add_deployed_modules(
+        &mut self,
+        library: &mut ModuleLibrary,
+        previous: Option<&ModuleLibrary>,
+    ) -> Result<(), anyhow::Error> {
+        if self.deploy_storage.is_none() {
+            return Ok(());
+        }
+
+        let storage = self.deploy_storage().map_err(|e| anyhow!("{e}"))?;
+        for (name, active) in Self::active_modules(&storage).map_err(|e| anyhow!("{e}"))? {
+            let code = storage
+                .get(&key_for_code(&active.hash))
+                .map_err(|e| anyhow!("{e}"))?
+                .ok_or_else(|| anyhow!("Missing code for deployed module {name}."))?;
+            let loaded = self.compile_bytes(name, &code, active.storages, previous)?;
+            library.replace(loaded)?;
+        }
+
+        Ok(())
+    }
+
+    /// Load the deployed modules on top of the modules already loaded.
+    pub fn load_deployed(&mut self) -> Result<(), anyhow::Error> {
+        let previous = self.store.data().modules();
+        let mut library = (*previous).clone();
+        self.add_deployed_modules(&mut library, None)?;
+        self.store.data_mut().set_modules(library);
+        Ok(())
+    }
+
+    /// Run the `migrate` export of a module if it has one, unless `storage`
+    /// records that this version of the module was already migrated. Without
+    /// a storage to record it, the migration always runs.
+    fn migrate_module(
+        &mut self,
+        loaded: &LoadedModule,
+        storage: Option<&mut StorageRef>,
+    ) -> Result<(), ManyError> {
+        if loaded.module.get_export("migrate").is_none() {
+            return Ok(());
+        }
+        let migrated_key = key_for_migrated(&loaded.hash);
+        if let Some(storage) = &storage {
+            if storage.get(&migrated_key)?.is_some() {
+                return Ok(());
+            }
+        }
+
+        info!(module = loaded.name.as_str(), "Running migration.");
+        let arg = minicbor::to_vec(()).map_err(ManyError::serialization_error)?;
+        refuel(&mut self.store).map_err(ManyError::unknown)?;
+        run_export(&mut self.store, loaded, "migrate", arg)?;
+        if let Some(storage) = storage {
+            storage.set(migrated_key, vec![1])?;
+        }
+        Ok(())
+    }
+
+    /// Run the migrations of all loaded modules, so modules upgraded through
+    /// the configuration migrate their data like deployed ones. Migrations are
+    /// recorded in the deploy storage if there is one. All storages are
+    /// committed if they succeed; otherwise they are rolled back.
+    pub fn migrate_modules(&mut self) -> Result<(), ManyError> {
+        let result = self.migrate_modules_uncommitted();
+        let storage = self.store.data_mut().storage_library_mut();
+        match result {
+            Ok(()) => storage.commit(),
+            Err(e) => {
+                if let Err(rollback) = storage.rollback() {
+                    error!(error = %rollback, "Could not roll back storages.");
+                }
+                Err(e)
+            }
+        }
+    }
+
+    fn migrate_modules_uncommitted(&mut self) -> Result<(), ManyError> {
+        let mut storage = match self.deploy_storage {
+            Some(_) => Some(self.deploy_storage()?),
+            None => None,
+        };
+        let modules = self.store.data().modules();
+        for loaded in modules.iter() {
+            self.migrate_module(loaded, storage.as_mut())?;
+        }
+        Ok(())
+    }
+
+    /// Store a module in the deploy storage, activate it and run its `migrate`
+    /// export (once per module hash) if it has one. The migration runs with the
+    /// new module active and its storage grants. All storages are committed if
+    /// it succeeds; otherwise they are rolled back and the previous modules are
+    /// kept.
+    /// Returns the hash of the module.
+    pub fn deploy(&mut self, args: DeployArgs) -> Result<Vec<u8>, ManyError> {
+        let previous = self.store.data().modules();
+        let result = self.deploy_uncommitted(args, &previous);
+        let data = self.store.data_mut();
+        match result {
+            Ok(hash) => {
+                data.storage_library_mut().commit()?;
+                Ok(hash)
+            }
+            Err(e) => {
+                data.set_modules((*previous).clone());
+                if let Err(rollback) = data.storage_library_mut().rollback() {
+                    error!(error = %rollback, "Could not roll back storages.");
+                }
+                Err(e)
+            }
+        }
+    }
+
+    fn deploy_uncommitted(
+        &mut self,
+        args: DeployArgs,
+        previous: &ModuleLibrary,
+    ) -> Result<Vec<u8>, ManyError> {
+        let mut storage = self.deploy_storage()?;
+        let DeployArgs {
+            name,
+            code,
+            storages,
+            signature,
+        } = args;
+        self.verifier
+            .check(&name, None, &code, signature.as_deref().map(Vec::as_slice))
+            .map_err(ManyError::unknown)?;
+
+        let storages = storages
+            .or_else(|| previous.by_name(&name).map(|m| m.storages.clone()))
+            .unwrap_or_default();
+        let loaded = self
+            .compile_bytes(name.clone(), &code, storages.clone(), Some(previous))
+            .map_err(ManyError::unknown)?;
+        let hash = loaded.hash.clone();
+
+        // Fails on conflicts before changing anything.
+        let mut library = previous.clone();
+        library
+            .replace(loaded.clone())
+            .map_err(ManyError::unknown)?;
+        self.store.data_mut().set_modules(library);
+
+        self.migrate_module(&loaded, Some(&mut storage))?;
+
+        let mut active = Self::active_modules(&storage)?;
+        active.insert(
+            name.clone(),
+            ActiveModule {
+                hash: hash.clone(),
+                storages,
+            },
+        );
+        storage.set(key_for_code(&hash), code)?;
+        storage.set(
+            ACTIVE_KEY.to_vec(),
+            minicbor::to_vec(active).map_err(ManyError::serialization_error)?,
+        )?;
+        info!(module = name.as_str(), hash = %hex::encode(&hash), "Deployed module.");
+
+        Ok(hash)
+    }
+}
@@ -1,12 +1,21 @@
+use crate::config::StorageAccess;
 use crate::storage::{StorageLibrary, StorageRef};
+use crate::wasm_engine::ModuleLibrary;
 use anyhow::Error;
 use many_error::ManyError;
 use many_identity::Address;
 use many_protocol::RequestMessage;
+use sha3::{Digest, Sha3_256};
 use std::collections::BTreeMap;
 use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
 use std::time::SystemTime;
+use tracing::warn;
 use wasi_common::WasiCtx;
+use wasmtime::Linker;
+
+/// Maximum number of nested `many::call` frames, including the top level request.
+pub const MAX_CALL_DEPTH: usize = 8;
 
 #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq)]
 #[repr(transparent)]
@@ -60,6 +69,7 @@ impl Into<usize> for RegistryHandle {
 pub enum RegistryObject {
     Error(ManyError),
     Storage(StorageRef),
+    CallResult(Result<Vec<u8>, ManyError>),
 }
 
 impl RegistryObject {
@@ -87,6 +97,18 @@ impl RegistryObject {
             _ => None,
         }
     }
+    pub fn as_call_result(&self) -> Option<&Result<Vec<u8>, ManyError>> {
+        match self {
+            RegistryObject::CallResult(r) => Some(r),
+            _ => None,
+        }
+    }
+    pub fn into_call_result(self) -> Option<Result<Vec<u8>, ManyError>> {
+        match self {
+            RegistryObject::CallResult(r) => Some(r),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Default)]
@@ -128,6 +150,23 @@ impl HandleRegistry {
     pub fn get_storage_mut(&mut self, handle: RegistryHandle) -> Option<&mut StorageRef> {
         self.inner.get_mut(&handle)?.as_storage_mut()
     }
+
+    pub fn create_call_result(&mut self, result: Result<Vec<u8>, ManyError>) -> RegistryHandle {
+        self.create(RegistryObject::CallResult(result))
+    }
+
+    pub fn get_call_result(&self, handle: RegistryHandle) -> Option<&Result<Vec<u8>, ManyError>> {
+        self.inner.get(&handle)?.as_call_result()
+    }
+
+    /// Remove a call result from the registry. Other objects are left as is.
+    pub fn take_call_result(
+        &mut self,
+        handle: RegistryHandle,
+    ) -> Option<Result<Vec<u8>, ManyError>> {
+        self.get_call_result(handle)?;
+        self.inner.remove(&handle)?.into_call_result()
+    }
 }
 
 pub enum CallContext {
@@ -160,6 +199,16 @@ impl CallContext {
         }
     }
 
+    /// The response set by a module executing a request.
+    pub fn into_response(self) -> Result<Result<Vec<u8>, ManyError>, Error> {
+        match self {
+            Self::ManyRequest(_, response) => {
+                response.ok_or_else(|| Error::msg("No return value was set"))
+            }
+            _ => Err(Error::msg("Invalid context")),
+        }
+    }
+
     pub fn as_many_request_mut(
         &mut self,
     ) -> Option<(&mut RequestMessage, &mut Option<Result<Vec<u8>, ManyError>>)> {
@@ -170,22 +219,49 @@ impl CallContext {
     }
 }
 
+/// A frame on the module call stack, kept while an endpoint is executing so
+/// nested calls can be restored to their caller's context.
+struct CallFrame {
+    module: String,
+    parent: CallContext,
+
+    /// Results of the calls made by this frame that were not read yet, which
+    /// are freed when it returns.
+    call_results: Vec<RegistryHandle>,
+}
+
 pub struct WasmContext {
     call_context: CallContext,
+    call_stack: Vec<CallFrame>,
     registry: HandleRegistry,
 
+    address: Address,
+    linker: Arc<Linker<WasmContext>>,
+    modules: Arc<ModuleLibrary>,
+
     storage_library: StorageLibrary,
     storage_cache: BTreeMap<String, RegistryHandle>,
+    storage_names: BTreeMap<RegistryHandle, String>,
     wasi_ctx: WasiCtx,
 }
 
 impl WasmContext {
-    pub fn new(storage_library: StorageLibrary, wasi_ctx: WasiCtx) -> Self {
+    pub fn new(
+        storage_library: StorageLibrary,
+        wasi_ctx: WasiCtx,
+        address: Address,
+        linker: Arc<Linker<WasmContext>>,
+    ) -> Self {
         Self {
             call_context: CallContext::None,
+            call_stack: Vec::new(),
             registry: Default::default(),
+            address,
+            linker,
+            modules: Default::default(),
             storage_library,
             storage_cache: BTreeMap::new(),
+            storage_names: BTreeMap::new(),
             wasi_ctx,
         }
     }
@@ -194,13 +270,102 @@ impl WasmContext {
         self.call_context = CallContext::None;
     }
 
-    pub fn response(&mut self) -> Result<Result<Vec<u8>, ManyError>, Error> {
-        match &mut self.call_context {
-            CallContext::ManyRequest(_, response) => response
-                .take()
-                .ok_or_else(|| Error::msg("No return value was set")),
-            _ => Err(Error::msg("Invalid context")),
+    pub fn linker(&self) -> Arc<Linker<WasmContext>> {
+        self.linker.clone()
+    }
+
+    pub fn modules(&self) -> Arc<ModuleLibrary> {
+        self.modules.clone()
+    }
+
+    pub fn modules_mut(&mut self) -> &mut ModuleLibrary {
+        Arc::make_mut(&mut self.modules)
+    }
+
+    pub fn storage_library(&self) -> &StorageLibrary {
+        &self.storage_library
+    }
+
+    pub fn storage_library_mut(&mut self) -> &mut StorageLibrary {
+        &mut self.storage_library
+    }
+
+    pub fn set_modules(&mut self, modules: ModuleLibrary) {
+        self.modules = Arc::new(modules);
+    }
+
+    /// The address of the server identity.
+    pub fn identity(&self) -> Address {
+        self.address
+    }
+
+    /// The address a module uses as the sender when it calls another module.
+    /// This is a subresource of the server identity, derived from the module name
+    /// so that it stays stable across restarts.
+    pub fn module_address(&self, module: &str) -> Result<Address, ManyError> {
+        let hash = Sha3_256::digest(module.as_bytes());
+        let id = u32::from_be_bytes([hash[0], hash[1], hash[2], hash[3]]) & 0x7FFF_FFFF;
+        self.address.with_subresource_id(id)
+    }
+
+    /// Push a new frame for `module` executing in `context`, with a savepoint
+    /// of all storages. Fails if the module is already executing (re-entrancy)
+    /// or if the maximum depth is reached.
+    pub fn enter(&mut self, module: &str, context: CallContext) -> Result<(), ManyError> {
+        if self.call_stack.len() >= MAX_CALL_DEPTH {
+            return Err(ManyError::unknown(format!(
+                "Maximum call depth ({MAX_CALL_DEPTH}) exceeded."
+            )));
+        }
+        if self.call_stack.iter().any(|f| f.module == module) {
+            return Err(ManyError::unknown(format!(
+                "Module {module} is already executing (re-entrant call)."
+            )));
+        }
+
+        self.storage_library.savepoint()?;
+        let parent = std::mem::replace(&mut self.call_context, context);
+        self.call_stack.push(CallFrame {
+            module: module.to_string(),
+            parent,
+            call_results: Vec::new(),
+        });
+        Ok(())
+    }
+
+    /// Pop the current frame, restoring the caller's context, and return the
+    /// context the module left, with its response.
+    ///
+    /// If the module trapped or responded with an error, all its writes are
+    /// discarded, so a failed nested call leaves no trace. Otherwise they are
+    /// kept in the pending writes of its caller. The frame is popped even if
+    /// the storages fail to do so, in which case the error is returned.
+    pub fn leave(&mut self, trapped: bool) -> Result<CallContext, ManyError> {
+        let frame = match self.call_stack.pop() {
+            Some(frame) => frame,
+            None => return Ok(std::mem::replace(&mut self.call_context, CallContext::None)),
+        };
+        for handle in frame.call_results {
+            self.registry.take_call_result(handle);
+        }
+        let context = std::mem::replace(&mut self.call_context, frame.parent);
+
+        let failed = trapped
+            || matches!(
+                context,
+                CallContext::ManyRequest(_, None) | CallContext::ManyRequest(_, Some(Err(_)))
+            );
+        if failed {
+            self.storage_library.rollback_to_savepoint()?;
+        } else {
+            self.storage_library.release_savepoint()?;
         }
+        Ok(context)
+    }
+
+    /// The name of the module currently executing, if any.
+    pub fn current_module(&self) -> Option<&str> {
+        self.call_stack.last().map(|f| f.module.as_str())
     }
 
     pub fn wasi_ctx(&self) -> &WasiCtx {
@@ -233,14 +398,17 @@ impl WasmContext {
         self.call_context
             .as_many_request()
             .map(|(req, _)| req)
-            .ok_or_else(Error::msg("Invalid context"))
+            .ok_or_else(|| Error::msg("Invalid context"))
     }
 
     pub fn payload_size(&self) -> Result<usize, Error> {
-        Ok(self.request()?.data.len())
+        Ok(self.payload_bytes()?.len())
     }
     pub fn payload_bytes(&self) -> Result<&[u8], Error> {
-        Ok(self.request()?.data.as_slice())
+        match &self.call_context {
+            CallContext::Initialize(arg) => Ok(arg.as_slice()),
+            _ => Ok(self.request()?.data.as_slice()),
+        }
     }
 
     pub fn sender(&self) -> Result<Address, Error> {
@@ -264,25 +432,105 @@ impl WasmContext {
         self.registry.error(code)
     }
 
+    pub fn create_call_result(&mut self, result: Result<Vec<u8>, ManyError>) -> RegistryHandle {
+        let handle = self.registry.create_call_result(result);
+        if let Some(frame) = self.call_stack.last_mut() {
+            frame.call_results.push(handle);
+        }
+        handle
+    }
+
+    /// Remove a call result, once the module has read it.
+    pub fn take_call_result(
+        &mut self,
+        handle: RegistryHandle,
+    ) -> Result<Result<Vec<u8>, ManyError>, Error> {
+        if let Some(frame) = self.call_stack.last_mut() {
+            frame.call_results.retain(|h| *h != handle);
+        }
+        self.registry
+            .take_call_result(handle)
+            .ok_or_else(|| Error::msg("invalid handle"))
+    }
+    pub fn get_call_result(
+        &self,
+        handle: RegistryHandle,
+    ) -> Result<&Result<Vec<u8>, ManyError>, Error> {
+        self.registry
+            .get_call_result(handle)
+            .ok_or_else(|| Error::msg("invalid handle"))
+    }
+
+    /// Check that the module currently executing was granted access to a storage.
+    /// When no module is executing (e.g. during genesis), all storages are accessible.
+    fn check_storage_access(
+        &self,
+        name: &str,
+        access: &str,
+        granted: impl FnOnce(&StorageAccess) -> bool,
+    ) -> Result<(), Error> {
+        let module = match self.current_module() {
+            Some(m) => m,
+            None => return Ok(()),
+        };
+
+        let allowed = self
+            .modules
+            .by_name(module)
+            .and_then(|m| m.storages.get(name))
+            .map_or(false, granted);
+        if allowed {
+            Ok(())
+        } else {
+            warn!(module, storage = name, access, "Storage access denied.");
+            Err(Error::msg(format!(
+                "Module {module} does not have {access} access to storage {name}."
+            )))
+        }
+    }
+
+    fn check_storage_handle(
+        &self,
+        handle: u32,
+        access: &str,
+        granted: impl FnOnce(&StorageAccess) -> bool,
+    ) -> Result<(), Error> {
+        let handle: RegistryHandle = handle.into();
+        let name = self
+            .storage_names
+            .get(&handle)
+            .ok_or_else(|| Error::msg("Unknown handle or not a storage."))?;
+        self.check_storage_access(name, access, granted)
+    }
+
     pub fn create_storage(&mut self, name: &str) -> Result<RegistryHandle, Error> {
+        self.check_storage_access(name, "any", |_| true)?;
+
         // Make sure it hasn't already been created.
-        Ok(*self.storage_cache.entry(name.to_string()).or_insert(
-            self.registry.create_storage(
-                self.storage_library
-                    .get(name)
-                    .ok_or_else(|| Error::msg("Unknown storage name."))?
-                    .clone(),
-            ),
-        ))
+        if let Some(handle) = self.storage_cache.get(name) {
+            return Ok(*handle);
+        }
+
+        let storage_ref = self
+            .storage_library
+            .get(name)
+            .ok_or_else(|| Error::msg("Unknown storage name."))?
+            .clone();
+        let handle = self.registry.create_storage(storage_ref);
+        self.storage_cache.insert(name.to_string(), handle);
+        self.storage_names.insert(handle, name.to_string());
+        Ok(handle)
     }
 
     pub fn get_storage(&self, handle: u32) -> Result<&StorageRef, Error> {
+        self.check_storage_handle(handle, "read", StorageAccess::can_read)?;
         self.registry
             .get_storage(handle.into())
             .ok_or_else(|| Error::msg("Unknown handle or not a storage."))
     }
 
     pub fn get_storage_mut(&mut self, handle: u32) -> Result<&mut StorageRef, Error> {
+        self.check_storage_handle(handle, "write", StorageAccess::can_write)?;
         self.registry
             .get_storage_mut(handle.into())
             .ok_or_else(|| Error::msg("Unknown handle or not a storage."))
@@ -7,6 +7,7 @@ extern "C" {
     pub fn payload_copy(ptr: u32, len: u32) -> u32;
     pub fn sender_size() -> u32;
     pub fn sender_copy(ptr: u32) -> u32;
+    pub fn identity_copy(ptr: u32) -> u32;
 
     // Return value stuff.
     // Error.
@@ -22,4 +23,9 @@ extern "C" {
 
     pub fn return_error(id: u32) -> ();
     pub fn return_data(ptr: u32, len: u32) -> ();
+
+    // Calls to other modules. The result is freed by `call_result_copy`.
+    pub fn call(endpoint_ptr: u32, endpoint_len: u32, payload_ptr: u32, payload_len: u32) -> u32;
+    pub fn call_result_size(handle: u32) -> u32;
+    pub fn call_result_copy(handle: u32, ptr: u32, len: u32) -> u32;
 }
@@ -7,4 +7,13 @@ extern "C" {
     pub fn size(handle: u32, key_ptr: u32, key_len: u32) -> u32;
     pub fn get(handle: u32, key_ptr: u32, key_len: u32, output_ptr: u32, output_len: u32) -> u32;
     pub fn set(handle: u32, key_ptr: u32, key_len: u32, value_ptr: u32, value_len: u32) -> ();
+    pub fn hash(handle: u32, output_ptr: u32, output_len: u32) -> u32;
+    pub fn del(handle: u32, key_ptr: u32, key_len: u32) -> ();
+    pub fn next_key(
+        handle: u32,
+        key_ptr: u32,
+        key_len: u32,
+        output_ptr: u32,
+        output_len: u32,
+    ) -> u32;
 }
@@ -17,6 +17,14 @@ pub mod many {
         Address::from_bytes(&bytes[..len as usize]).expect("Invalid address from host")
     }
 
+    /// The address of the server identity.
+    pub fn identity() -> Address {
+        let mut bytes = vec![0u8; 32];
+
+        let len = unsafe { many::identity_copy(bytes.as_mut_ptr() as u32) };
+        Address::from_bytes(&bytes[..len as usize]).expect("Invalid address from host")
+    }
+
     pub fn payload() -> Vec<u8> {
         let payload_size = unsafe { many::payload_size() };
         let bytes: Vec<u8> = vec![0u8; payload_size as usize];
@@ -34,6 +42,27 @@ pub mod many {
         result
     }
 
+    /// The argument of an init module, as the raw JSON bytes from the config.
+    pub fn init_arg() -> Result<Vec<u8>, ManyError> {
+        decode::<minicbor::bytes::ByteVec>(&payload()).map(|arg| arg.to_vec())
+    }
+
+    /// Decode the payload, call the endpoint implementation and set its return
+    /// value (or error).
+    pub fn endpoint<Args, Returns>(f: impl FnOnce(Args) -> Result<Returns, ManyError>)
+    where
+        Args: for<'a> minicbor::Decode<'a, ()>,
+        Returns: minicbor::Encode<()>,
+    {
+        let result = decode(&payload())
+            .and_then(f)
+            .and_then(|r| minicbor::to_vec(r).map_err(ManyError::serialization_error));
+        match result {
+            Ok(result) => set_return_data(result),
+            Err(err) => set_return_error(err),
+        }
+    }
+
     pub fn set_return_error(err: ManyError) {
         unsafe {
             let handle = error_create(Into::<i64>::into(err.code()) as i32);
@@ -58,11 +87,33 @@ pub mod many {
     pub fn set_return_data(data: Vec<u8>) {
         unsafe { many::return_data(data.as_ptr() as u32, data.len() as u32) }
     }
+
+    /// Call another endpoint registered on this server, within the same
+    /// transaction. The sender of the call will be the address of this module.
+    pub fn call(endpoint: &str, payload: &[u8]) -> Result<Vec<u8>, ManyError> {
+        unsafe {
+            let handle = many::call(
+                endpoint.as_ptr() as u32,
+                endpoint.len() as u32,
+                payload.as_ptr() as u32,
+                payload.len() as u32,
+            );
+            let size = many::call_result_size(handle);
+            let bytes: Vec<u8> = vec![0u8; size as usize];
+            match many::call_result_copy(handle, bytes.as_ptr() as u32, size) {
+                0 => Ok(bytes),
+                _ => Err(decode::<ManyError>(&bytes)?),
+            }
+        }
+    }
 }
 
 pub mod store {
     use crate::host::store;
 
+    mod typed;
+    pub use typed::{StorageKey, TypedMap};
+
     pub struct Storage(u32);
 
     impl Storage {
@@ -98,5 +149,49 @@ pub mod store {
                 );
             }
         }
+
+        pub fn del(&self, key: &[u8]) {
+            unsafe {
+                store::del(self.0, key.as_ptr() as u32, key.len() as u32);
+            }
+        }
+
+        /// The first key strictly after `key`, in byte order, if any.
+        pub fn next_key(&self, key: &[u8]) -> Option<Vec<u8>> {
+            let mut buffer: Vec<u8> = vec![0u8; 64];
+            loop {
+                let len = unsafe {
+                    store::next_key(
+                        self.0,
+                        key.as_ptr() as u32,
+                        key.len() as u32,
+                        buffer.as_ptr() as u32,
+                        buffer.len() as u32,
+                    )
+                };
+                if len == u32::MAX {
+                    return None;
+                } else if len as usize > buffer.len() {
+                    buffer = vec![0u8; len as usize];
+                } else {
+                    buffer.truncate(len as usize);
+                    return Some(buffer);
+                }
+            }
+        }
+
+        /// The root hash of the storage, as of the last commit. For prefixed
+        /// storages, this is the hash of the backend storage.
+        pub fn hash(&self) -> Vec<u8> {
+            let mut buffer: Vec<u8> = vec![0u8; 64];
+            let len = unsafe { store::hash(self.0, buffer.as_ptr() as u32, buffer.len() as u32) };
+            if len as usize > buffer.len() {
+                buffer = vec![0u8; len as usize];
+                unsafe { store::hash(self.0, buffer.as_ptr() as u32, len) };
+            }
+            buffer.truncate(len as usize);
+
+            buffer
+        }
     }
 }
@@ -0,0 +1,317 @@
+use super::Storage;
+use many_error::ManyError;
+use many_identity::Address;
+use std::marker::PhantomData;
+
+/// A type that can be used as a key in a [`TypedMap`].
+///
+/// Encodings must preserve the ordering of keys and be self-delimiting, so that
+/// composite keys (tuples) built by concatenation cannot collide.
+pub trait StorageKey: Sized {
+    /// Append the encoded key to `out`.
+    fn encode_key(&self, out: &mut Vec<u8>);
+
+    /// Decode a key from the start of `bytes`, returning it and the remaining bytes.
+    fn decode_key(bytes: &[u8]) -> Option<(Self, &[u8])>;
+
+    fn to_key(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.encode_key(&mut out);
+        out
+    }
+}
+
+macro_rules! decl_int_keys {
+    ($($t: ty),* $(,)?) => {
+        $(
+        impl StorageKey for $t {
+            fn encode_key(&self, out: &mut Vec<u8>) {
+                out.extend_from_slice(&self.to_be_bytes());
+            }
+
+            fn decode_key(bytes: &[u8]) -> Option<(Self, &[u8])> {
+                const SIZE: usize = std::mem::size_of::<$t>();
+                let value = <$t>::from_be_bytes(bytes.get(..SIZE)?.try_into().ok()?);
+                Some((value, &bytes[SIZE..]))
+            }
+        }
+        )*
+    };
+}
+
+decl_int_keys!(u8, u16, u32, u64, u128);
+
+/// Bytes are escaped (`0x00` becomes `0x00 0xFF`) and terminated by `0x00 0x00`,
+/// which keeps the byte order and makes them self-delimiting.
+fn encode_bytes(bytes: &[u8], out: &mut Vec<u8>) {
+    for b in bytes {
+        out.push(*b);
+        if *b == 0 {
+            out.push(0xFF);
+        }
+    }
+    out.extend_from_slice(&[0, 0]);
+}
+
+fn decode_bytes(bytes: &[u8]) -> Option<(Vec<u8>, &[u8])> {
+    let mut result = Vec::new();
+    let mut i = 0;
+    loop {
+        match (bytes.get(i)?, bytes.get(i + 1)) {
+            (0, Some(0)) => return Some((result, &bytes[i + 2..])),
+            (0, Some(0xFF)) => {
+                result.push(0);
+                i += 2;
+            }
+            (0, _) => return None,
+            (b, _) => {
+                result.push(*b);
+                i += 1;
+            }
+        }
+    }
+}
+
+impl StorageKey for Vec<u8> {
+    fn encode_key(&self, out: &mut Vec<u8>) {
+        encode_bytes(self, out)
+    }
+
+    fn decode_key(bytes: &[u8]) -> Option<(Self, &[u8])> {
+        decode_bytes(bytes)
+    }
+}
+
+impl StorageKey for String {
+    fn encode_key(&self, out: &mut Vec<u8>) {
+        encode_bytes(self.as_bytes(), out)
+    }
+
+    fn decode_key(bytes: &[u8]) -> Option<(Self, &[u8])> {
+        let (bytes, rest) = decode_bytes(bytes)?;
+        Some((String::from_utf8(bytes).ok()?, rest))
+    }
+}
+
+impl StorageKey for Address {
+    fn encode_key(&self, out: &mut Vec<u8>) {
+        encode_bytes(&self.to_vec(), out)
+    }
+
+    fn decode_key(bytes: &[u8]) -> Option<(Self, &[u8])> {
+        let (bytes, rest) = decode_bytes(bytes)?;
+        Some((Address::from_bytes(&bytes).ok()?, rest))
+    }
+}
+
+impl<A: StorageKey, B: StorageKey> StorageKey for (A, B) {
+    fn encode_key(&self, out: &mut Vec<u8>) {
+        self.0.encode_key(out);
+        self.1.encode_key(out);
+    }
+
+    fn decode_key(bytes: &[u8]) -> Option<(Self, &[u8])> {
+        let (a, bytes) = A::decode_key(bytes)?;
+        let (b, bytes) = B::decode_key(bytes)?;
+        Some(((a, b), bytes))
+    }
+}
+
+impl<A: StorageKey, B: StorageKey, C: StorageKey> StorageKey for (A, B, C) {
+    fn encode_key(&self, out: &mut Vec<u8>) {
+        self.0.encode_key(out);
+        self.1.encode_key(out);
+        self.2.encode_key(out);
+    }
+
+    fn decode_key(bytes: &[u8]) -> Option<(Self, &[u8])> {
+        let (a, bytes) = A::decode_key(bytes)?;
+        let (b, bytes) = B::decode_key(bytes)?;
+        let (c, bytes) = C::decode_key(bytes)?;
+        Some(((a, b, c), bytes))
+    }
+}
+
+/// A map of CBOR encoded values over a storage, with all keys under a prefix.
+pub struct TypedMap<K, V> {
+    storage: Storage,
+    prefix: Vec<u8>,
+    _phantom: PhantomData<(K, V)>,
+}
+
+impl<K, V> TypedMap<K, V>
+where
+    K: StorageKey,
+    V: minicbor::Encode<()> + for<'a> minicbor::Decode<'a, ()>,
+{
+    pub fn new(storage: Storage, prefix: impl Into<Vec<u8>>) -> Self {
+        Self {
+            storage,
+            prefix: prefix.into(),
+            _phantom: PhantomData,
+        }
+    }
+
+    fn key(&self, key: &K) -> Vec<u8> {
+        let mut out = self.prefix.clone();
+        key.encode_key(&mut out);
+        out
+    }
+
+    /// Values are never empty once encoded, so an empty value means the key
+    /// does not exist.
+    fn decode_value(bytes: Vec<u8>) -> Result<Option<V>, ManyError> {
+        if bytes.is_empty() {
+            Ok(None)
+        } else {
+            minicbor::decode(&bytes)
+                .map(Some)
+                .map_err(ManyError::deserialization_error)
+        }
+    }
+
+    pub fn get(&self, key: &K) -> Result<Option<V>, ManyError> {
+        Self::decode_value(self.storage.get(&self.key(key)))
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        !self.storage.get(&self.key(key)).is_empty()
+    }
+
+    pub fn insert(&self, key: &K, value: &V) -> Result<(), ManyError> {
+        let bytes = minicbor::to_vec(value).map_err(ManyError::serialization_error)?;
+        self.storage.set(&self.key(key), &bytes);
+        Ok(())
+    }
+
+    pub fn remove(&self, key: &K) {
+        self.storage.del(&self.key(key))
+    }
+
+    /// Iterate over all entries of the map, in key order.
+    pub fn iter(&self) -> Iter<'_, K, V> {
+        Iter {
+            map: self,
+            last: self.prefix.clone(),
+        }
+    }
+}
+
+pub struct Iter<'a, K, V> {
+    map: &'a TypedMap<K, V>,
+    last: Vec<u8>,
+}
+
+impl<'a, K, V> Iterator for Iter<'a, K, V>
+where
+    K: StorageKey,
+    V: minicbor::Encode<()> + for<'b> minicbor::Decode<'b, ()>,
+{
+    type Item = Result<(K, V), ManyError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let next = self.map.storage.next_key(&self.last)?;
+            if !next.starts_with(&self.map.prefix) {
+                return None;
+            }
+
+            let key = K::decode_key(&next[self.map.prefix.len()..]);
+            let value = TypedMap::<K, V>::decode_value(self.map.storage.get(&next));
+            self.last = next;
+
+            // Skip deleted keys, which have an empty value.
+            return match (key, value) {
+                (_, Ok(None)) => continue,
+                (Some((key, [])), Ok(Some(value))) => Some(Ok((key, value))),
+                (Some((_, [])), Err(e)) => Some(Err(e)),
+                _ => Some(Err(ManyError::unknown("Invalid key in typed map."))),
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys<K: StorageKey>(keys: &[K]) -> Vec<Vec<u8>> {
+        keys.iter().map(StorageKey::to_key).collect()
+    }
+
+    fn assert_sorted(encoded: &[Vec<u8>]) {
+        for pair in encoded.windows(2) {
+            assert!(pair[0] < pair[1], "{:?} >= {:?}", pair[0], pair[1]);
+        }
+    }
+
+    #[test]
+    fn int_order() {
+        assert_sorted(&keys(&[0u64, 1, 255, 256, u64::MAX]));
+        assert_sorted(&keys(&[0u128, 1 << 64, u128::MAX]));
+    }
+
+    #[test]
+    fn bytes_order() {
+        // Sorted in byte order, including prefixes and zero bytes.
+        let sorted: Vec<Vec<u8>> = vec![
+            vec![],
+            vec![0],
+            vec![0, 0],
+            vec![0, 1],
+            vec![0, 0xFF],
+            vec![1],
+            vec![1, 0],
+            vec![0xFF],
+            vec![0xFF, 0xFF],
+        ];
+        assert_sorted(&keys(&sorted));
+    }
+
+    #[test]
+    fn tuple_order() {
+        let sorted = [
+            (vec![], 1u8),
+            (vec![0], 0),
+            (vec![0, 0], 0),
+            (vec![1], 0),
+            (vec![1], 1),
+            (vec![1, 0], 0),
+        ];
+        assert_sorted(&keys(&sorted));
+    }
+
+    #[test]
+    fn no_collision() {
+        // Concatenations that would collide without escaping and terminators.
+        let pairs: Vec<(Vec<u8>, Vec<u8>)> = vec![
+            (vec![], vec![1]),
+            (vec![1], vec![]),
+            (vec![0], vec![]),
+            (vec![], vec![0]),
+            (vec![0, 0], vec![]),
+            (vec![0], vec![0]),
+            (vec![], vec![0, 0]),
+            (vec![0, 0xFF], vec![]),
+        ];
+        let mut encoded = keys(&pairs);
+        encoded.sort();
+        encoded.dedup();
+        assert_eq!(encoded.len(), pairs.len());
+    }
+
+    #[test]
+    fn roundtrip() {
+        let key = (vec![0u8, 0xFF, 0, 0], "a\0b".to_string(), 42u32);
+        let encoded = key.to_key();
+        assert_eq!(
+            <(Vec<u8>, String, u32)>::decode_key(&encoded),
+            Some((key, &[][..]))
+        );
+
+        // Truncated and unterminated encodings are rejected.
+        assert_eq!(Vec::<u8>::decode_key(&[1, 2]), None);
+        assert_eq!(Vec::<u8>::decode_key(&[0, 1]), None);
+        assert_eq!(u32::decode_key(&[0, 0, 1]), None);
+    }
+}
@@ -0,0 +1,151 @@
+extern crate wee_alloc;
+
+// Use `wee_alloc` as the global allocator.
+#[global_allocator]
+static ALLOC: wee_alloc::WeeAlloc = wee_alloc::WeeAlloc::INIT;
+
+use many_error::ManyError;
+use many_identity::Address;
+use many_modules::ledger::{
+    TokenBurnArgs, TokenBurnReturns, TokenCreateArgs, TokenCreateReturns, TokenInfoArgs,
+    TokenInfoReturns, TokenMintArgs, TokenMintReturns, TokenUpdateArgs, TokenUpdateReturns,
+};
+use many_types::ledger::{Symbol, TokenAmount, TokenInfo, TokenInfoSummary, TokenInfoSupply};
+use many_wasm::many::{endpoint, sender};
+use many_wasm::store::Storage;
+use storage_tokens::Tokens;
+
+/// The token info in the `many_types` format.
+fn token_info(symbol: Symbol, info: storage_tokens::TokenInfo) -> TokenInfo {
+    TokenInfo {
+        symbol,
+        summary: TokenInfoSummary {
+            name: info.name,
+            ticker: info.symbol,
+            decimals: info.precision,
+        },
+        supply: TokenInfoSupply {
+            total: info.supply.clone(),
+            circulating: info.supply,
+            maximum: None,
+        },
+        owner: info.owner,
+    }
+}
+
+#[export_name = "init"]
+pub fn init() {
+    // Make sure those storages are available.
+    let _ = Storage::by_name("tokens");
+    let _ = Storage::by_name("balances");
+}
+
+/// Declares the tokens attributes (12 to 14) in the server status. Attribute
+/// 11 is not declared, as extended info is not supported.
+#[export_name = "attribute 12"]
+pub fn attribute_12() {}
+#[export_name = "attribute 13"]
+pub fn attribute_13() {}
+#[export_name = "attribute 14"]
+pub fn attribute_14() {}
+
+#[export_name = "endpoint tokens.info"]
+pub fn info() {
+    endpoint(|args: TokenInfoArgs| {
+        let info = Tokens::new().info(args.symbol)?;
+        Ok(TokenInfoReturns {
+            info: token_info(args.symbol, info),
+            extended_info: Default::default(),
+        })
+    })
+}
+
+#[export_name = "endpoint tokens.create"]
+pub fn create() {
+    endpoint(|args: TokenCreateArgs| {
+        let sender = sender();
+        if sender.is_anonymous() {
+            return Err(ManyError::unknown("Anonymous cannot create tokens."));
+        }
+        if args.maximum_supply.is_some() || args.extended_info.is_some() {
+            return Err(ManyError::unknown(
+                "Maximum supply and extended info are not supported.",
+            ));
+        }
+
+        // Without owner, the sender owns the token. An explicit empty owner
+        // makes it immutable.
+        let owner: Option<Address> = match args.owner {
+            None => Some(sender),
+            Some(owner) => owner.left(),
+        };
+
+        let tokens = Tokens::new();
+        let symbol = tokens.next_symbol()?;
+        let info = storage_tokens::TokenInfo {
+            name: args.summary.name,
+            symbol: args.summary.ticker,
+            precision: args.summary.decimals,
+            supply: TokenAmount::from(0u64),
+            owner,
+        };
+        tokens.set(symbol, &info)?;
+
+        if let Some(distribution) = args.initial_distribution {
+            tokens.mint(symbol, &distribution)?;
+        }
+
+        Ok(TokenCreateReturns {
+            info: token_info(symbol, tokens.info(symbol)?),
+        })
+    })
+}
+
+#[export_name = "endpoint tokens.update"]
+pub fn update() {
+    endpoint(|args: TokenUpdateArgs| {
+        let tokens = Tokens::new();
+        let mut info = tokens.info(args.symbol)?;
+        info.check_owner(&sender())?;
+
+        if let Some(name) = args.name {
+            info.name = name;
+        }
+        if let Some(ticker) = args.ticker {
+            info.symbol = ticker;
+        }
+        if let Some(decimals) = args.decimals {
+            info.precision = decimals;
+        }
+        if let Some(owner) = args.owner {
+            info.owner = owner.left();
+        }
+        tokens.set(args.symbol, &info)?;
+
+        Ok(TokenUpdateReturns {})
+    })
+}
+
+#[export_name = "endpoint tokens.mint"]
+pub fn mint() {
+    endpoint(|args: TokenMintArgs| {
+        let tokens = Tokens::new();
+        tokens.info(args.symbol)?.check_owner(&sender())?;
+        tokens.mint(args.symbol, &args.distribution)?;
+
+        Ok(TokenMintReturns {})
+    })
+}
+
+#[export_name = "endpoint tokens.burn"]
+pub fn burn() {
+    endpoint(|args: TokenBurnArgs| {
+        let tokens = Tokens::new();
+        tokens.info(args.symbol)?.check_owner(&sender())?;
+        tokens.burn(args.symbol, &args.distribution)?;
+
+        Ok(TokenBurnReturns {
+            distribution: args.distribution,
+        })
+    })
+}
@@ -4,37 +4,95 @@ extern crate wee_alloc;
 #[global_allocator]
 static ALLOC: wee_alloc::WeeAlloc = wee_alloc::WeeAlloc::INIT;
 
-use many_error::ManyError;
-use many_modules::ledger::{BalanceArgs, BalanceReturns};
-use many_wasm::many::payload;
+use many_modules::ledger::errors;
+use many_modules::ledger::{BalanceArgs, BalanceReturns, InfoArgs, InfoReturns, SendArgs};
+use many_wasm::many::endpoint;
 use many_wasm::store::Storage;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 use storage_ledger::LedgerAccount;
 
 #[export_name = "init"]
 pub fn init() {
-    // Make sure this storage is available.
+    // Make sure those storages are available.
     let _ = Storage::by_name("balances");
+    let _ = Storage::by_name("tokens");
+    let _ = Storage::by_name("events");
+}
+
+/// Called once per version of this module, when it is deployed or loaded.
+#[export_name = "migrate"]
+pub fn migrate() {
+    if let Err(e) = storage_ledger::migrate_legacy_keys() {
+        panic!("Could not migrate balances: {e}");
+    }
+}
+
+/// Declares the ledger attribute (2) in the server status.
+#[export_name = "attribute 2"]
+pub fn attribute() {}
+
+#[export_name = "endpoint ledger.info"]
+pub fn info() {
+    endpoint(|_args: InfoArgs| {
+        let symbols = storage_ledger::symbols()?;
+
+        Ok(InfoReturns {
+            symbols: symbols.keys().copied().collect(),
+            hash: Storage::by_name("balances").hash().into(),
+            local_names: symbols,
+        })
+    })
 }
 
 #[export_name = "endpoint ledger.balance"]
 pub fn balance() {
-    fn balance_(args: BalanceArgs) -> Result<BalanceReturns, ManyError> {
+    endpoint(|args: BalanceArgs| {
         let sender = many_wasm::many::sender();
-        println!(r#"Sender: "{}""#, sender);
-
         let account: LedgerAccount = args.account.unwrap_or(sender).into();
 
-        // Try to decode bytes.
-        eprintln!("balance: {:?}", args);
-        Ok(many_modules::ledger::BalanceReturns {
-            balances: BTreeMap::new(),
-        })
-    }
+        let symbols = storage_ledger::symbols()?;
+        let balances = match args.symbols {
+            None => account
+                .balances()
+                .into_iter()
+                .filter(|(symbol, amount)| symbols.contains_key(symbol) && !amount.is_zero())
+                .collect(),
+            Some(requested) => {
+                let mut balances = BTreeMap::new();
+                for symbol in requested.into_iter().collect::<BTreeSet<_>>() {
+                    if !symbols.contains_key(&symbol) {
+                        return Err(errors::unknown_symbol(symbol));
+                    }
+                    let amount = account.balance(symbol);
+                    if !amount.is_zero() {
+                        balances.insert(symbol, amount);
+                    }
+                }
+                balances
+            }
+        };
 
-    let args = many_wasm::many::decode(&payload()).and_then(balance_);
-    match args {
-        Ok(result) => many_wasm::many::set_return_data(minicbor::to_vec(result).unwrap()),
-        Err(err) => many_wasm::many::set_return_error(err),
-    }
+        Ok(BalanceReturns { balances })
+    })
+}
+
+#[export_name = "endpoint ledger.send"]
+pub fn send() {
+    endpoint(|args: SendArgs| {
+        let sender = many_wasm::many::sender();
+        let from = args.from.unwrap_or(sender);
+        if from != sender {
+            return Err(errors::unauthorized());
+        }
+        if !storage_ledger::symbols()?.contains_key(&args.symbol) {
+            return Err(errors::unknown_symbol(args.symbol));
+        }
+
+        let from: LedgerAccount = from.into();
+        let to: LedgerAccount = args.to.into();
+        from.send(&to, args.symbol, args.amount)?;
+
+        // Empty map.
+        Ok(BTreeMap::<u8, ()>::new())
+    })
 }
@@ -0,0 +1,113 @@
+extern crate wee_alloc;
+
+// Use `wee_alloc` as the global allocator.
+#[global_allocator]
+static ALLOC: wee_alloc::WeeAlloc = wee_alloc::WeeAlloc::INIT;
+
+use many_error::ManyError;
+use many_identity::Address;
+use many_wasm::many::{endpoint, sender};
+use minicbor::bytes::ByteVec;
+use minicbor::{Decode, Encode};
+use std::collections::BTreeMap;
+use storage_kvstore::KvStore;
+
+type EmptyArgs = BTreeMap<u8, ()>;
+type EmptyReturns = BTreeMap<u8, ()>;
+
+#[derive(Encode, Decode)]
+#[cbor(map)]
+pub struct InfoReturns {
+    #[n(0)]
+    pub hash: ByteVec,
+}
+
+#[derive(Encode, Decode)]
+#[cbor(map)]
+pub struct KeyArgs {
+    #[n(0)]
+    pub key: ByteVec,
+}
+
+#[derive(Encode, Decode)]
+#[cbor(map)]
+pub struct GetReturns {
+    #[n(0)]
+    pub value: Option<ByteVec>,
+}
+
+#[derive(Encode, Decode)]
+#[cbor(map)]
+pub struct PutArgs {
+    #[n(0)]
+    pub key: ByteVec,
+    #[n(1)]
+    pub value: ByteVec,
+}
+
+#[derive(Encode, Decode)]
+#[cbor(map)]
+pub struct QueryReturns {
+    #[n(0)]
+    pub owner: Address,
+    #[n(1)]
+    pub disabled: bool,
+}
+
+#[export_name = "init"]
+pub fn init() {
+    // Make sure this storage is available.
+    let _ = KvStore::new();
+}
+
+/// Declares the kvstore attribute (3) in the server status.
+#[export_name = "attribute 3"]
+pub fn attribute() {}
+
+#[export_name = "endpoint kvstore.info"]
+pub fn info() {
+    endpoint(|_args: EmptyArgs| {
+        Ok(InfoReturns {
+            hash: KvStore::new().hash().into(),
+        })
+    })
+}
+
+#[export_name = "endpoint kvstore.get"]
+pub fn get() {
+    endpoint(|args: KeyArgs| {
+        let value = KvStore::new().get(&args.key)?;
+        Ok(GetReturns {
+            value: value.map(Into::into),
+        })
+    })
+}
+
+#[export_name = "endpoint kvstore.query"]
+pub fn query() {
+    endpoint(|args: KeyArgs| {
+        let meta = KvStore::new()
+            .metadata(&args.key)?
+            .ok_or_else(|| ManyError::unknown("Unknown key."))?;
+        Ok(QueryReturns {
+            owner: meta.owner,
+            disabled: meta.disabled,
+        })
+    })
+}
+
+#[export_name = "endpoint kvstore.put"]
+pub fn put() {
+    endpoint(|args: PutArgs| {
+        KvStore::new().put(&sender(), &args.key, &args.value)?;
+        Ok(EmptyReturns::new())
+    })
+}
+
+#[export_name = "endpoint kvstore.disable"]
+pub fn disable() {
+    endpoint(|args: KeyArgs| {
+        KvStore::new().disable(&sender(), &args.key)?;
+        Ok(EmptyReturns::new())
+    })
+}
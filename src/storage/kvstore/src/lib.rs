@@ -0,0 +1,164 @@
+use many_error::ManyError;
+use many_identity::Address;
+use many_wasm::store::Storage;
+use minicbor::{Decode, Encode};
+
+fn key_for_meta(key: &[u8]) -> Vec<u8> {
+    [b"meta/".as_slice(), key].concat()
+}
+
+fn key_for_value(key: &[u8]) -> Vec<u8> {
+    [b"value/".as_slice(), key].concat()
+}
+
+/// Metadata kept for every key in the store.
+#[derive(Clone, Debug, Encode, Decode)]
+#[cbor(map)]
+pub struct KeyMetadata {
+    /// Only the owner can write to or disable the key.
+    #[n(0)]
+    pub owner: Address,
+
+    /// A disabled key has no value and cannot be written to anymore.
+    #[n(1)]
+    pub disabled: bool,
+}
+
+/// A key-value store where every key has an owner, in the `kvstore` storage.
+pub struct KvStore(Storage);
+
+impl Default for KvStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl KvStore {
+    pub fn new() -> Self {
+        KvStore(Storage::by_name("kvstore"))
+    }
+
+    pub fn hash(&self) -> Vec<u8> {
+        self.0.hash()
+    }
+
+    pub fn metadata(&self, key: &[u8]) -> Result<Option<KeyMetadata>, ManyError> {
+        let bytes = self.0.get(&key_for_meta(key));
+        if bytes.is_empty() {
+            Ok(None)
+        } else {
+            minicbor::decode(&bytes)
+                .map(Some)
+                .map_err(ManyError::deserialization_error)
+        }
+    }
+
+    fn set_metadata(&self, key: &[u8], metadata: &KeyMetadata) -> Result<(), ManyError> {
+        let bytes = minicbor::to_vec(metadata).map_err(ManyError::serialization_error)?;
+        self.0.set(&key_for_meta(key), &bytes);
+        Ok(())
+    }
+
+    /// Check that `sender` can write to `key`, returning its metadata if it exists.
+    fn check_writable(
+        &self,
+        sender: &Address,
+        key: &[u8],
+    ) -> Result<Option<KeyMetadata>, ManyError> {
+        check_writable(sender, self.metadata(key)?)
+    }
+
+    /// The value of a key, or `None` if it doesn't exist or is disabled.
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, ManyError> {
+        match self.metadata(key)? {
+            Some(meta) if !meta.disabled => Ok(Some(self.0.get(&key_for_value(key)))),
+            _ => Ok(None),
+        }
+    }
+
+    /// Set the value of a key. New keys are owned by `owner`.
+    pub fn put(&self, owner: &Address, key: &[u8], value: &[u8]) -> Result<(), ManyError> {
+        if self.check_writable(owner, key)?.is_none() {
+            self.set_metadata(
+                key,
+                &KeyMetadata {
+                    owner: *owner,
+                    disabled: false,
+                },
+            )?;
+        }
+        self.0.set(&key_for_value(key), value);
+        Ok(())
+    }
+
+    /// Disable a key, removing its value.
+    pub fn disable(&self, owner: &Address, key: &[u8]) -> Result<(), ManyError> {
+        let mut meta = self
+            .check_writable(owner, key)?
+            .ok_or_else(|| ManyError::unknown("Unknown key."))?;
+        meta.disabled = true;
+
+        self.set_metadata(key, &meta)?;
+        self.0.del(&key_for_value(key));
+        Ok(())
+    }
+}
+
+/// Check that `sender` can write to a key with the metadata `meta`, which is
+/// `None` for new keys.
+fn check_writable(
+    sender: &Address,
+    meta: Option<KeyMetadata>,
+) -> Result<Option<KeyMetadata>, ManyError> {
+    if sender.is_anonymous() {
+        return Err(ManyError::unknown("Anonymous cannot own keys."));
+    }
+
+    match meta {
+        Some(meta) if meta.disabled => Err(ManyError::unknown("The key is disabled.")),
+        Some(meta) if &meta.owner != sender => Err(ManyError::unknown(
+            "Only the owner of the key can write to it.",
+        )),
+        meta => Ok(meta),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address(id: u32) -> Address {
+        Address::from_bytes(&[[1u8].as_slice(), &[0u8; 28]].concat())
+            .unwrap()
+            .with_subresource_id(id)
+            .unwrap()
+    }
+
+    fn meta(owner: Address, disabled: bool) -> Option<KeyMetadata> {
+        Some(KeyMetadata { owner, disabled })
+    }
+
+    #[test]
+    fn new_key() {
+        assert!(check_writable(&address(1), None).unwrap().is_none());
+    }
+
+    #[test]
+    fn owner_only() {
+        let existing = check_writable(&address(1), meta(address(1), false)).unwrap();
+        assert_eq!(existing.unwrap().owner, address(1));
+        assert!(check_writable(&address(2), meta(address(1), false)).is_err());
+    }
+
+    #[test]
+    fn disabled_key() {
+        assert!(check_writable(&address(1), meta(address(1), true)).is_err());
+        assert!(check_writable(&address(2), meta(address(1), true)).is_err());
+    }
+
+    #[test]
+    fn anonymous_owner() {
+        assert!(check_writable(&Address::anonymous(), None).is_err());
+        assert!(check_writable(&Address::anonymous(), meta(Address::anonymous(), false)).is_err());
+    }
+}
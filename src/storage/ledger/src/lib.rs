@@ -1,9 +1,151 @@
+use many_error::ManyError;
 use many_identity::Address;
-use many_types::ledger::TokenAmount;
+use many_modules::ledger::errors;
+use many_types::ledger::{Symbol, TokenAmount};
 use many_wasm::store::Storage;
+use minicbor::{Decode, Encode};
+use std::collections::BTreeMap;
+use std::str::FromStr;
 
+/// The key of a balance is the length of the account address bytes, the
+/// account address bytes, then the symbol address bytes. All balances of an
+/// account share the same key prefix (see [`key_prefix_for_address`]).
 fn key_for_address_balance(address: Address, symbol: Address) -> Vec<u8> {
-    format!("{}/{symbol}", address).into_bytes()
+    let mut key = key_prefix_for_address(address);
+    key.extend(symbol.to_vec());
+    key
+}
+
+fn key_prefix_for_address(address: Address) -> Vec<u8> {
+    let address = address.to_vec();
+    let mut key = Vec::with_capacity(1 + address.len() + 32);
+    key.push(address.len() as u8);
+    key.extend(address);
+    key
+}
+
+fn address_and_symbol_from_key(key: &[u8]) -> Option<(Address, Symbol)> {
+    let (len, rest) = key.split_first()?;
+    let len = *len as usize;
+    if rest.len() <= len {
+        return None;
+    }
+    let address = Address::from_bytes(&rest[..len]).ok()?;
+    let symbol = Address::from_bytes(&rest[len..]).ok()?;
+    Some((address, symbol))
+}
+
+/// Keys were previously formatted as `"{address}/{symbol}"`. Those start with
+/// the textual form of an address (`m...`), which a length byte never is.
+fn legacy_key_to_address_and_symbol(key: &[u8]) -> Option<(Address, Symbol)> {
+    let (address, symbol) = std::str::from_utf8(key).ok()?.split_once('/')?;
+    Some((
+        Address::from_str(address).ok()?,
+        Address::from_str(symbol).ok()?,
+    ))
+}
+
+/// Rewrite all balances stored with the legacy string keys to the binary key
+/// schema. Returns the number of balances migrated. This is meant to be called
+/// from the `migrate` export of the ledger module, which the server runs when
+/// a new version of the module is deployed or loaded.
+pub fn migrate_legacy_keys() -> Result<usize, ManyError> {
+    let storage = Storage::by_name("balances");
+    let mut count = 0;
+    let mut last = Vec::new();
+
+    while let Some(key) = storage.next_key(&last) {
+        if let Some((address, symbol)) = legacy_key_to_address_and_symbol(&key) {
+            let value = storage.get(&key);
+            storage.set(&key_for_address_balance(address, symbol), &value);
+            storage.del(&key);
+            count += 1;
+        }
+        last = key;
+    }
+
+    Ok(count)
+}
+
+/// Parse a decimal amount, allowing `_` as separators (e.g. `1_000_000`).
+pub fn parse_amount(amount: &str) -> Result<TokenAmount, String> {
+    let digits = amount.chars().filter(|c| *c != '_').collect::<String>();
+    if digits.is_empty() {
+        return Err("empty amount".to_string());
+    }
+
+    // Big-endian base 256 bytes, which is how `TokenAmount` is stored.
+    let mut bytes: Vec<u8> = Vec::new();
+    for c in digits.chars() {
+        let mut carry = c
+            .to_digit(10)
+            .ok_or_else(|| format!("invalid digit {c:?}"))?;
+        for b in bytes.iter_mut().rev() {
+            let v = (*b as u32) * 10 + carry;
+            *b = (v & 0xFF) as u8;
+            carry = v >> 8;
+        }
+        if carry > 0 {
+            bytes.insert(0, carry as u8);
+        }
+    }
+
+    Ok(TokenAmount::from(bytes))
+}
+
+const SYMBOLS_KEY: &[u8] = b"symbols";
+const EVENTS_COUNT_KEY: &[u8] = b"count";
+
+fn key_for_event(id: u64) -> Vec<u8> {
+    [b"event/".as_slice(), &id.to_be_bytes()].concat()
+}
+
+/// Symbols supported by the ledger, mapped to their ticker. Those are kept in
+/// the `tokens` storage.
+pub fn symbols() -> Result<BTreeMap<Symbol, String>, ManyError> {
+    let bytes = Storage::by_name("tokens").get(SYMBOLS_KEY);
+    if bytes.is_empty() {
+        Ok(BTreeMap::new())
+    } else {
+        minicbor::decode(&bytes).map_err(ManyError::deserialization_error)
+    }
+}
+
+pub fn set_symbols(symbols: &BTreeMap<Symbol, String>) -> Result<(), ManyError> {
+    let bytes = minicbor::to_vec(symbols).map_err(ManyError::serialization_error)?;
+    Storage::by_name("tokens").set(SYMBOLS_KEY, &bytes);
+    Ok(())
+}
+
+/// An event recorded for each transfer of tokens.
+#[derive(Clone, Debug, Encode, Decode)]
+#[cbor(map)]
+pub struct TransferEvent {
+    #[n(0)]
+    pub from: Address,
+    #[n(1)]
+    pub to: Address,
+    #[n(2)]
+    pub symbol: Symbol,
+    #[n(3)]
+    pub amount: TokenAmount,
+}
+
+impl TransferEvent {
+    /// Append the event to the `events` storage.
+    pub fn emit(&self) -> Result<(), ManyError> {
+        let storage = Storage::by_name("events");
+        let count = storage.get(EVENTS_COUNT_KEY);
+        let id = match count.as_slice().try_into() {
+            Ok(bytes) => u64::from_be_bytes(bytes),
+            Err(_) => 0,
+        };
+
+        let bytes = minicbor::to_vec(self).map_err(ManyError::serialization_error)?;
+        storage.set(&key_for_event(id), &bytes);
+        storage.set(EVENTS_COUNT_KEY, &(id + 1).to_be_bytes());
+        Ok(())
+    }
 }
 
 #[repr(C)]
@@ -14,6 +156,10 @@ impl LedgerAccount {
         LedgerAccount(Storage::by_name("balances"), address)
     }
 
+    pub fn address(&self) -> Address {
+        self.1
+    }
+
     pub fn set(&self, symbol: Address, amount: TokenAmount) {
         let key = key_for_address_balance(self.1, symbol);
         self.0.set(&key, &amount.to_vec());
@@ -21,6 +167,63 @@ impl LedgerAccount {
     pub fn balance(&self, symbol: Address) -> TokenAmount {
         TokenAmount::from(self.0.get(&key_for_address_balance(self.1, symbol)))
     }
+
+    /// All balances stored for this account.
+    pub fn balances(&self) -> BTreeMap<Symbol, TokenAmount> {
+        let prefix = key_prefix_for_address(self.1);
+        let mut balances = BTreeMap::new();
+        let mut last = prefix.clone();
+
+        while let Some(key) = self.0.next_key(&last) {
+            if !key.starts_with(&prefix) {
+                break;
+            }
+            if let Some((_, symbol)) = address_and_symbol_from_key(&key) {
+                balances.insert(symbol, TokenAmount::from(self.0.get(&key)));
+            }
+            last = key;
+        }
+
+        balances
+    }
+
+    /// Transfer `amount` of `symbol` from this account to `to`. All checks are
+    /// done before any balance is modified.
+    pub fn send(
+        &self,
+        to: &LedgerAccount,
+        symbol: Symbol,
+        amount: TokenAmount,
+    ) -> Result<(), ManyError> {
+        if amount.is_zero() {
+            return Err(errors::amount_is_zero());
+        }
+        if self.1 == to.1 {
+            return Err(errors::destination_is_source());
+        }
+        if to.1.is_anonymous() {
+            return Err(errors::anonymous_cannot_hold_funds());
+        }
+
+        let mut from_balance = self.balance(symbol);
+        if from_balance < amount {
+            return Err(errors::insufficient_funds());
+        }
+        let mut to_balance = to.balance(symbol);
+
+        from_balance -= amount.clone();
+        to_balance += amount.clone();
+        self.set(symbol, from_balance);
+        to.set(symbol, to_balance);
+
+        TransferEvent {
+            from: self.1,
+            to: to.1,
+            symbol,
+            amount,
+        }
+        .emit()
+    }
 }
 
 impl Into<LedgerAccount> for Address {
@@ -28,3 +231,106 @@ impl Into<LedgerAccount> for Address {
         LedgerAccount::new(self)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeSet;
+
+    fn addresses() -> Vec<Address> {
+        let public_keys = [
+            Address::from_str("maffbahksdwaqeenayy2gxke32hgb7aq4ao4wt745lsfs6wijp").unwrap(),
+            Address::from_bytes(&[[1u8].as_slice(), &[0u8; 28]].concat()).unwrap(),
+            Address::from_bytes(&[[1u8].as_slice(), &[0xFFu8; 28]].concat()).unwrap(),
+        ];
+        let mut addresses = vec![Address::anonymous()];
+        for address in public_keys {
+            addresses.push(address);
+            for id in [0, 1, 2, 255, 256, 0x7FFF_FFFF] {
+                addresses.push(address.with_subresource_id(id).unwrap());
+            }
+        }
+        addresses
+    }
+
+    #[test]
+    fn keys_are_unique() {
+        let addresses = addresses();
+        let mut keys = BTreeSet::new();
+        for account in &addresses {
+            for symbol in &addresses {
+                assert!(
+                    keys.insert(key_for_address_balance(*account, *symbol)),
+                    "Collision for ({account}, {symbol})"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn keys_roundtrip() {
+        let addresses = addresses();
+        for account in &addresses {
+            for symbol in &addresses {
+                let key = key_for_address_balance(*account, *symbol);
+                assert_eq!(address_and_symbol_from_key(&key), Some((*account, *symbol)));
+                assert!(key.starts_with(&key_prefix_for_address(*account)));
+            }
+        }
+    }
+
+    #[test]
+    fn amounts() {
+        assert_eq!(parse_amount("0"), Ok(TokenAmount::from(0u64)));
+        assert_eq!(parse_amount("1_000"), Ok(TokenAmount::from(1000u64)));
+        assert_eq!(
+            parse_amount("100_000_000__000_000_000"),
+            Ok(TokenAmount::from(100_000_000_000_000_000u64))
+        );
+        assert_eq!(
+            parse_amount("18446744073709551615"),
+            Ok(TokenAmount::from(u64::MAX))
+        );
+    }
+
+    #[test]
+    fn amounts_above_u64() {
+        // 2^64 and 2^128.
+        assert_eq!(
+            parse_amount("18_446_744_073_709_551_616"),
+            Ok(TokenAmount::from(
+                [1u8].into_iter().chain([0; 8]).collect::<Vec<u8>>()
+            ))
+        );
+        assert_eq!(
+            parse_amount("340282366920938463463374607431768211456"),
+            Ok(TokenAmount::from(
+                [1u8].into_iter().chain([0; 16]).collect::<Vec<u8>>()
+            ))
+        );
+    }
+
+    #[test]
+    fn invalid_amounts() {
+        for amount in ["", "_", "1.5", "-1", "1e9", "0x10", " 1", "１"] {
+            assert!(parse_amount(amount).is_err(), "{amount:?}");
+        }
+    }
+
+    #[test]
+    fn legacy_keys() {
+        let addresses = addresses();
+        for account in &addresses {
+            for symbol in &addresses {
+                let legacy = format!("{account}/{symbol}").into_bytes();
+                assert_eq!(
+                    legacy_key_to_address_and_symbol(&legacy),
+                    Some((*account, *symbol))
+                );
+                // New keys are never mistaken for legacy ones.
+                let key = key_for_address_balance(*account, *symbol);
+                assert_eq!(legacy_key_to_address_and_symbol(&key), None);
+            }
+        }
+    }
+}
@@ -0,0 +1,271 @@
+use many_error::ManyError;
+use many_identity::Address;
+use many_types::ledger::{Symbol, TokenAmount};
+use many_wasm::store::Storage;
+use minicbor::{Decode, Encode};
+use std::collections::BTreeMap;
+use storage_ledger::LedgerAccount;
+
+const NEXT_ID_KEY: &[u8] = b"next_id";
+
+fn key_for_info(symbol: Symbol) -> Vec<u8> {
+    [b"info/".as_slice(), &symbol.to_vec()].concat()
+}
+
+/// Information about a token, as stored in the `tokens` storage.
+#[derive(Clone, Debug, Encode, Decode)]
+#[cbor(map)]
+pub struct TokenInfo {
+    #[n(0)]
+    pub name: String,
+
+    /// The ticker (e.g. `MFX`).
+    #[n(1)]
+    pub symbol: String,
+
+    /// Number of decimals.
+    #[n(2)]
+    pub precision: u64,
+
+    /// Total supply.
+    #[n(3)]
+    pub supply: TokenAmount,
+
+    /// The owner is the only one who can update, mint and burn the token. A
+    /// token without owner cannot be changed.
+    #[n(4)]
+    pub owner: Option<Address>,
+}
+
+impl TokenInfo {
+    pub fn check_owner(&self, sender: &Address) -> Result<(), ManyError> {
+        match &self.owner {
+            Some(owner) if owner == sender => Ok(()),
+            _ => Err(ManyError::unknown("Only the owner can change this token.")),
+        }
+    }
+}
+
+pub struct Tokens(Storage);
+
+impl Default for Tokens {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Tokens {
+    pub fn new() -> Self {
+        Tokens(Storage::by_name("tokens"))
+    }
+
+    pub fn get(&self, symbol: Symbol) -> Result<Option<TokenInfo>, ManyError> {
+        let bytes = self.0.get(&key_for_info(symbol));
+        if bytes.is_empty() {
+            Ok(None)
+        } else {
+            minicbor::decode(&bytes)
+                .map(Some)
+                .map_err(ManyError::deserialization_error)
+        }
+    }
+
+    pub fn info(&self, symbol: Symbol) -> Result<TokenInfo, ManyError> {
+        self.get(symbol)?
+            .ok_or_else(|| ManyError::unknown(format!("Unknown token symbol {symbol}.")))
+    }
+
+    /// Set the info of a token, registering its symbol with the ledger.
+    pub fn set(&self, symbol: Symbol, info: &TokenInfo) -> Result<(), ManyError> {
+        let bytes = minicbor::to_vec(info).map_err(ManyError::serialization_error)?;
+        self.0.set(&key_for_info(symbol), &bytes);
+
+        let mut symbols = storage_ledger::symbols()?;
+        symbols.insert(symbol, info.symbol.clone());
+        storage_ledger::set_symbols(&symbols)
+    }
+
+    /// Allocate a new symbol address, as a subresource of the server identity.
+    pub fn next_symbol(&self) -> Result<Symbol, ManyError> {
+        let id = next_id(&self.0.get(NEXT_ID_KEY));
+        self.0.set(NEXT_ID_KEY, &(id + 1).to_be_bytes());
+
+        many_wasm::many::identity().with_subresource_id(id)
+    }
+
+    /// Credit every account in `distribution` and increase the supply.
+    pub fn mint(
+        &self,
+        symbol: Symbol,
+        distribution: &BTreeMap<Address, TokenAmount>,
+    ) -> Result<(), ManyError> {
+        let mut info = self.info(symbol)?;
+        let balances = minted(&mut info, distribution, |address| {
+            LedgerAccount::from(*address).balance(symbol)
+        });
+        for (address, balance) in balances {
+            LedgerAccount::from(address).set(symbol, balance);
+        }
+        self.set(symbol, &info)
+    }
+
+    /// Debit every account in `distribution` and decrease the supply. Fails
+    /// without changing anything if any account has insufficient funds.
+    pub fn burn(
+        &self,
+        symbol: Symbol,
+        distribution: &BTreeMap<Address, TokenAmount>,
+    ) -> Result<(), ManyError> {
+        let mut info = self.info(symbol)?;
+        let balances = burned(&mut info, distribution, |address| {
+            LedgerAccount::from(*address).balance(symbol)
+        })?;
+        for (address, balance) in balances {
+            LedgerAccount::from(address).set(symbol, balance);
+        }
+        self.set(symbol, &info)
+    }
+}
+
+/// The id of the next symbol, from the stored counter.
+fn next_id(bytes: &[u8]) -> u32 {
+    match bytes.try_into() {
+        Ok(bytes) => u32::from_be_bytes(bytes),
+        // Subresource 0 is left unused.
+        Err(_) => 1,
+    }
+}
+
+/// The new balance of every account in `distribution` after minting, adding
+/// the minted amounts to the supply of `info`.
+fn minted(
+    info: &mut TokenInfo,
+    distribution: &BTreeMap<Address, TokenAmount>,
+    balance: impl Fn(&Address) -> TokenAmount,
+) -> BTreeMap<Address, TokenAmount> {
+    let mut balances = BTreeMap::new();
+    for (address, amount) in distribution {
+        let mut new_balance = balance(address);
+        new_balance += amount.clone();
+        info.supply += amount.clone();
+        balances.insert(*address, new_balance);
+    }
+    balances
+}
+
+/// The new balance of every account in `distribution` after burning,
+/// removing the burnt amounts from the supply of `info`. Nothing is changed
+/// if any account has insufficient funds.
+fn burned(
+    info: &mut TokenInfo,
+    distribution: &BTreeMap<Address, TokenAmount>,
+    balance: impl Fn(&Address) -> TokenAmount,
+) -> Result<BTreeMap<Address, TokenAmount>, ManyError> {
+    let mut balances = BTreeMap::new();
+    for (address, amount) in distribution {
+        let mut new_balance = balance(address);
+        if new_balance < *amount {
+            return Err(ManyError::unknown(format!(
+                "Insufficient funds to burn from {address}."
+            )));
+        }
+        new_balance -= amount.clone();
+        balances.insert(*address, new_balance);
+    }
+
+    for amount in distribution.values() {
+        info.supply -= amount.clone();
+    }
+    Ok(balances)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address(id: u32) -> Address {
+        Address::from_bytes(&[[1u8].as_slice(), &[0u8; 28]].concat())
+            .unwrap()
+            .with_subresource_id(id)
+            .unwrap()
+    }
+
+    fn info(supply: u64) -> TokenInfo {
+        TokenInfo {
+            name: "Test".to_string(),
+            symbol: "TST".to_string(),
+            precision: 9,
+            supply: supply.into(),
+            owner: Some(address(0)),
+        }
+    }
+
+    fn distribution(amounts: &[(u32, u64)]) -> BTreeMap<Address, TokenAmount> {
+        amounts
+            .iter()
+            .map(|(id, amount)| (address(*id), (*amount).into()))
+            .collect()
+    }
+
+    #[test]
+    fn next_ids() {
+        assert_eq!(next_id(&[]), 1);
+        assert_eq!(next_id(&5u32.to_be_bytes()), 5);
+    }
+
+    #[test]
+    fn mint() {
+        let existing = distribution(&[(1, 10)]);
+        let balance = |a: &Address| existing.get(a).cloned().unwrap_or_default();
+
+        let mut info = info(10);
+        let balances = minted(&mut info, &distribution(&[(1, 5), (2, 7)]), balance);
+        assert_eq!(balances, distribution(&[(1, 15), (2, 7)]));
+        assert_eq!(info.supply, 22u64.into());
+    }
+
+    #[test]
+    fn burn() {
+        let existing = distribution(&[(1, 10), (2, 3)]);
+        let balance = |a: &Address| existing.get(a).cloned().unwrap_or_default();
+
+        let mut info = info(13);
+        let balances = burned(&mut info, &distribution(&[(1, 4), (2, 3)]), balance).unwrap();
+        assert_eq!(balances, distribution(&[(1, 6), (2, 0)]));
+        assert_eq!(info.supply, 6u64.into());
+    }
+
+    #[test]
+    fn burn_is_all_or_nothing() {
+        let existing = distribution(&[(1, 10), (2, 3)]);
+        let balance = |a: &Address| existing.get(a).cloned().unwrap_or_default();
+
+        // The first account has enough funds, the second doesn't.
+        let mut info = info(13);
+        assert!(burned(&mut info, &distribution(&[(1, 4), (2, 4)]), balance).is_err());
+        assert!(burned(&mut info, &distribution(&[(3, 1)]), balance).is_err());
+        assert_eq!(info.supply, 13u64.into());
+    }
+
+    #[test]
+    fn owner() {
+        let info = info(0);
+        assert!(info.check_owner(&address(0)).is_ok());
+        assert!(info.check_owner(&address(1)).is_err());
+
+        let info = TokenInfo {
+            owner: None,
+            ..info
+        };
+        assert!(info.check_owner(&address(0)).is_err());
+    }
+
+    #[test]
+    fn info_roundtrip() {
+        let bytes = minicbor::to_vec(info(42)).unwrap();
+        let decoded: TokenInfo = minicbor::decode(&bytes).unwrap();
+        assert_eq!(decoded.supply, 42u64.into());
+        assert_eq!(decoded.owner, Some(address(0)));
+        assert_eq!(decoded.symbol, "TST");
+    }
+}